@@ -0,0 +1,23 @@
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Bakes the git commit and build time into the binary for /version. A
+// GIT_COMMIT variable wins over asking git, for builds without a .git dir.
+fn main() {
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/refs");
+
+    let commit = std::env::var("GIT_COMMIT")
+        .ok()
+        .filter(|commit| !commit.trim().is_empty())
+        .or_else(|| {
+            let output = Command::new("git").args(["rev-parse", "--short=12", "HEAD"]).output().ok()?;
+            output.status.success().then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        })
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=PROXY_GIT_COMMIT={}", commit.trim());
+
+    let built = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    println!("cargo:rustc-env=PROXY_BUILD_TIMESTAMP={built}");
+}
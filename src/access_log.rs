@@ -0,0 +1,179 @@
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use axum::body::{Body, HttpBody};
+use axum::extract::{Request, State};
+use axum::middleware::Next;
+use axum::response::Response;
+use futures_util::StreamExt;
+use serde::Serialize;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+
+use crate::client_ip::{ForwardedHop, client_ip};
+
+// lines waiting for the writer; past that new ones are dropped rather than
+// holding up requests
+const QUEUE_LEN: usize = 8192;
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+enum Message {
+    Line(String),
+    Flush(oneshot::Sender<()>),
+}
+
+// Structured per-request log kept apart from the tracing output. Lines are
+// written by a background task through a buffer flushed every second.
+#[derive(Clone)]
+pub struct AccessLog {
+    tx: mpsc::Sender<Message>,
+}
+
+#[derive(Serialize)]
+struct Entry {
+    // unix seconds, millisecond precision
+    ts: f64,
+    client: Option<IpAddr>,
+    method: String,
+    path: String,
+    // upstream host of a /fetch-style request
+    host: Option<String>,
+    status: u16,
+    bytes: u64,
+    // until the response headers were ready
+    latency_ms: u64,
+}
+
+impl AccessLog {
+    // Has to be called inside the tokio runtime, the writer is spawned on it.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(path)?;
+        let (tx, rx) = mpsc::channel(QUEUE_LEN);
+        tokio::spawn(write_lines(tokio::fs::File::from_std(file), rx));
+        Ok(AccessLog { tx })
+    }
+
+    fn record(&self, entry: &Entry) {
+        let Ok(mut line) = serde_json::to_string(entry) else {
+            return;
+        };
+        line.push('\n');
+        if self.tx.try_send(Message::Line(line)).is_err() {
+            warn!("access log writer is behind, dropping entry");
+        }
+    }
+
+    // Waits until everything recorded so far is on disk.
+    pub async fn flush(&self) {
+        let (done, flushed) = oneshot::channel();
+        if self.tx.send(Message::Flush(done)).await.is_ok() {
+            let _ = flushed.await;
+        }
+    }
+}
+
+async fn write_lines(file: tokio::fs::File, mut rx: mpsc::Receiver<Message>) {
+    let mut out = BufWriter::new(file);
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    let mut dirty = false;
+    loop {
+        tokio::select! {
+            message = rx.recv() => match message {
+                Some(Message::Line(line)) => {
+                    if let Err(e) = out.write_all(line.as_bytes()).await {
+                        warn!("failed to write access log: {e}");
+                    }
+                    dirty = true;
+                }
+                Some(Message::Flush(done)) => {
+                    flush(&mut out, &mut dirty).await;
+                    let _ = done.send(());
+                }
+                None => break,
+            },
+            _ = interval.tick() => flush(&mut out, &mut dirty).await,
+        }
+    }
+    flush(&mut out, &mut dirty).await;
+}
+
+async fn flush(out: &mut BufWriter<tokio::fs::File>, dirty: &mut bool) {
+    if std::mem::take(dirty)
+        && let Err(e) = out.flush().await
+    {
+        warn!("failed to flush access log: {e}");
+    }
+}
+
+// Middleware recording every request. Bodies of unknown size are counted as
+// they go out, and their line is written once the body is done.
+pub async fn log_requests(
+    State((log, trust_forwarded)): State<(AccessLog, Option<ForwardedHop>)>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let started = Instant::now();
+    let mut entry = Entry {
+        ts: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as f64 / 1000.0,
+        client: client_ip(request.headers(), request.extensions(), trust_forwarded),
+        method: request.method().to_string(),
+        path: request.uri().path().to_string(),
+        host: request.uri().query().and_then(target_host),
+        status: 0,
+        bytes: 0,
+        latency_ms: 0,
+    };
+
+    let res = next.run(request).await;
+    entry.status = res.status().as_u16();
+    entry.latency_ms = started.elapsed().as_millis() as u64;
+
+    if let Some(len) = res.body().size_hint().exact() {
+        entry.bytes = len;
+        log.record(&entry);
+        return res;
+    }
+    let (parts, body) = res.into_parts();
+    let mut pending = Pending { log, entry };
+    let body = body.into_data_stream().map(move |chunk| {
+        if let Ok(chunk) = &chunk {
+            pending.sent(chunk.len());
+        }
+        chunk
+    });
+    Response::from_parts(parts, Body::from_stream(body))
+}
+
+// Written when a streamed body finishes or the client goes away.
+struct Pending {
+    log: AccessLog,
+    entry: Entry,
+}
+
+impl Pending {
+    // through a method so the closure owns all of `self`, not a copy of the count
+    fn sent(&mut self, len: usize) {
+        self.entry.bytes += len as u64;
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        self.log.record(&self.entry);
+    }
+}
+
+// Host of the `b64` or `url` parameter, whichever /fetch would use.
+fn target_host(query: &str) -> Option<String> {
+    let mut url = None;
+    for (name, value) in url::form_urlencoded::parse(query.as_bytes()) {
+        match &*name {
+            "b64" => url = crate::links::decode_b64_url(&value),
+            "url" if url.is_none() => url = Some(value.into_owned()),
+            _ => {}
+        }
+    }
+    url::Url::parse(&url?).ok()?.host_str().map(str::to_string)
+}
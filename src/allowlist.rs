@@ -0,0 +1,27 @@
+// Upstream hosts the proxy may fetch from, as configured in PROXY_ALLOWED_HOSTS.
+// Entries are exact host names or `*.example.com` to match any subdomain.
+#[derive(Clone)]
+pub struct HostAllowlist {
+    patterns: Vec<String>,
+}
+
+impl HostAllowlist {
+    pub fn parse(value: &str) -> Self {
+        let patterns = value
+            .split(',')
+            .map(|p| p.trim().trim_end_matches('.').to_ascii_lowercase())
+            .filter(|p| !p.is_empty())
+            .collect();
+        HostAllowlist { patterns }
+    }
+
+    pub fn allows(&self, host: &str) -> bool {
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        self.patterns.iter().any(|pattern| match pattern.strip_prefix("*.") {
+            Some(suffix) => host
+                .strip_suffix(suffix)
+                .is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+            None => *pattern == host,
+        })
+    }
+}
@@ -0,0 +1,243 @@
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::body::Bytes;
+use axum::http::{HeaderMap, StatusCode, header};
+use lru::LruCache;
+use url::Url;
+
+// An upstream response kept in memory, before any playlist rewriting.
+pub struct CachedResponse {
+    // where the body came from after redirects, the base of its relative links
+    pub url: Url,
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    stored: Instant,
+    ttl: Duration,
+}
+
+impl CachedResponse {
+    pub fn is_fresh(&self) -> bool {
+        self.stored.elapsed() < self.ttl
+    }
+
+    pub fn age(&self) -> Duration {
+        self.stored.elapsed()
+    }
+
+    // Whether the body is the whole object, so byte ranges can be cut from it.
+    // .ts segments are fetched with `Range: bytes=0-`, which origins may
+    // answer with a 206 that still covers everything.
+    pub fn is_complete(&self) -> bool {
+        match self.status {
+            StatusCode::OK => true,
+            StatusCode::PARTIAL_CONTENT => {
+                let len = self.body.len();
+                self.headers
+                    .get(header::CONTENT_RANGE)
+                    .and_then(|v| v.to_str().ok())
+                    .is_some_and(|v| len > 0 && v == format!("bytes 0-{}/{len}", len - 1))
+            }
+            _ => false,
+        }
+    }
+
+    // rough footprint used against the byte budget
+    fn size(&self) -> usize {
+        let headers: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        self.body.len() + headers + self.url.as_str().len()
+    }
+}
+
+// LRU cache of upstream responses keyed by upstream URL, bounded by a total
+// byte budget (PROXY_CACHE_BYTES). Only whole objects are stored: player
+// Range requests are answered from a cached full segment when there is one
+// and passed through otherwise. That means a segment only ever seen in parts
+// is never cached, but the budget never holds overlapping pieces of one
+// object either.
+//
+// Expired entries are kept for another `max_stale` (PROXY_STALE_IF_ERROR_SECS)
+// so they can stand in for an upstream that is failing.
+pub struct Cache {
+    budget: usize,
+    max_stale: Duration,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    entries: LruCache<String, Arc<CachedResponse>>,
+    bytes: usize,
+}
+
+impl Cache {
+    pub fn new(budget: usize, max_stale: Duration) -> Self {
+        Cache {
+            budget,
+            max_stale,
+            inner: Mutex::new(Inner {
+                entries: LruCache::unbounded(),
+                bytes: 0,
+            }),
+        }
+    }
+
+    // Largest body worth buffering for the cache.
+    pub fn max_entry_bytes(&self) -> usize {
+        self.budget
+    }
+
+    // Returns the entry for `key` if it hasn't expired yet.
+    pub fn get_fresh(&self, key: &str) -> Option<Arc<CachedResponse>> {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner.entries.get(key).cloned()?;
+        if entry.is_fresh() {
+            return Some(entry);
+        }
+        if entry.age() >= entry.ttl + self.max_stale {
+            inner.entries.pop(key);
+            inner.bytes -= entry.size();
+        }
+        None
+    }
+
+    // Returns the entry for `key` if it has expired, but by less than max_stale.
+    pub fn get_stale(&self, key: &str) -> Option<Arc<CachedResponse>> {
+        let mut inner = self.inner.lock().unwrap();
+        let entry = inner.entries.get(key).cloned()?;
+        (!entry.is_fresh() && entry.age() < entry.ttl + self.max_stale).then_some(entry)
+    }
+
+    pub fn insert(&self, key: String, url: Url, status: StatusCode, headers: HeaderMap, body: Bytes, ttl: Duration) {
+        let entry = CachedResponse {
+            url,
+            status,
+            headers,
+            body,
+            stored: Instant::now(),
+            ttl,
+        };
+        let size = entry.size();
+        if size > self.budget {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(previous) = inner.entries.put(key, Arc::new(entry)) {
+            inner.bytes -= previous.size();
+        }
+        inner.bytes += size;
+        while inner.bytes > self.budget {
+            match inner.entries.pop_lru() {
+                Some((_, evicted)) => inner.bytes -= evicted.size(),
+                None => break,
+            }
+        }
+    }
+}
+
+// How long an upstream response may be cached: the Cache-Control s-maxage or
+// max-age when present, `default` when the origin sent no Cache-Control, and
+// None when it must not be cached at all.
+pub fn ttl_from_headers(headers: &HeaderMap, default: Duration) -> Option<Duration> {
+    let Some(cache_control) = headers.get(header::CACHE_CONTROL).and_then(|v| v.to_str().ok()) else {
+        return Some(default);
+    };
+
+    let mut max_age = None;
+    let mut s_maxage = None;
+    for directive in cache_control.split(',').map(|d| d.trim().to_ascii_lowercase()) {
+        if matches!(directive.as_str(), "no-store" | "no-cache" | "private") {
+            return None;
+        }
+        if let Some(secs) = directive.strip_prefix("s-maxage=") {
+            s_maxage = secs.parse::<u64>().ok();
+        } else if let Some(secs) = directive.strip_prefix("max-age=") {
+            max_age = secs.parse::<u64>().ok();
+        }
+    }
+
+    match s_maxage.or(max_age) {
+        Some(0) => None,
+        Some(secs) => Some(Duration::from_secs(secs)),
+        None => Some(default),
+    }
+}
+
+// Normalized form of an upstream URL, used as its cache key and in logs.
+// Parsing already lowercased the host and dropped a default port; the
+// fragment never reaches the origin and an empty query is the same as none.
+// With `sort_query` parameters are sorted by name, keeping the relative order
+// of repeated names. Path case is always left alone.
+pub fn normalize_url(url: &Url, sort_query: bool) -> String {
+    let mut url = url.clone();
+    url.set_fragment(None);
+    match url.query().map(str::to_string) {
+        Some(query) if query.is_empty() => url.set_query(None),
+        Some(query) if sort_query => {
+            let mut pairs: Vec<&str> = query.split('&').filter(|pair| !pair.is_empty()).collect();
+            pairs.sort_by_key(|pair| pair.split('=').next().unwrap_or(pair));
+            url.set_query(Some(&pairs.join("&")));
+        }
+        _ => {}
+    }
+    url.into()
+}
+
+// Resolves a single-range `bytes=` Range header against a body of `len`
+// bytes. None for anything not served from the cache, i.e. multiple ranges,
+// other units, malformed or unsatisfiable ranges; the origin answers those.
+pub fn resolve_range(value: &str, len: usize) -> Option<Range<usize>> {
+    let spec = value.trim().strip_prefix("bytes=")?;
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = if start.is_empty() {
+        // suffix range, the last `end` bytes
+        let suffix: usize = end.parse().ok()?;
+        len.saturating_sub(suffix)..len
+    } else {
+        let start: usize = start.parse().ok()?;
+        let end = match end {
+            "" => len,
+            end => end.parse::<usize>().ok()?.checked_add(1)?.min(len),
+        };
+        start..end
+    };
+    (range.start < range.end).then_some(range)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls_are_normalized() {
+        let url = Url::parse("HTTPS://CDN.Example.com:443/Live/Seg.ts?b=2&a=1&b=1#t=5").unwrap();
+        assert_eq!(normalize_url(&url, false), "https://cdn.example.com/Live/Seg.ts?b=2&a=1&b=1");
+        assert_eq!(normalize_url(&url, true), "https://cdn.example.com/Live/Seg.ts?a=1&b=2&b=1");
+
+        let url = Url::parse("http://cdn.example.com:8080/seg.ts?").unwrap();
+        assert_eq!(normalize_url(&url, true), "http://cdn.example.com:8080/seg.ts");
+    }
+
+    #[test]
+    fn ranges_are_resolved_against_the_body() {
+        assert_eq!(resolve_range("bytes=0-", 10), Some(0..10));
+        assert_eq!(resolve_range("bytes=2-4", 10), Some(2..5));
+        assert_eq!(resolve_range("bytes=5-100", 10), Some(5..10));
+        assert_eq!(resolve_range("bytes=-3", 10), Some(7..10));
+        assert_eq!(resolve_range("bytes=10-", 10), None);
+        assert_eq!(resolve_range("bytes=4-2", 10), None);
+        assert_eq!(resolve_range("bytes=0-1,4-5", 10), None);
+        assert_eq!(resolve_range("items=0-1", 10), None);
+    }
+}
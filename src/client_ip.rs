@@ -0,0 +1,70 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::extract::ConnectInfo;
+use axum::http::{Extensions, HeaderMap};
+
+// Which X-Forwarded-For entry is the client when PROXY_TRUST_FORWARDED is set.
+// Every proxy appends the address it got the request from, so `Last` is the
+// one written by the load balancer right in front of us, while `First` is the
+// original client as long as every hop in the chain is trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardedHop {
+    First,
+    Last,
+}
+
+// The client address used for rate limiting and logs. X-Forwarded-For is only
+// looked at when `trusted` is set, otherwise any client could pick its own
+// address; unparseable entries fall back to the socket peer.
+pub fn client_ip(headers: &HeaderMap, extensions: &Extensions, trusted: Option<ForwardedHop>) -> Option<IpAddr> {
+    let peer = extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let Some(hop) = trusted else {
+        return peer;
+    };
+
+    // several X-Forwarded-For headers count as one comma-separated list
+    let entries: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .collect();
+    let entry = match hop {
+        ForwardedHop::First => entries.first(),
+        ForwardedHop::Last => entries.last(),
+    };
+    entry.and_then(|entry| entry.parse().ok()).or(peer)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn request_parts(forwarded: &[&'static str]) -> (HeaderMap, Extensions) {
+        let mut headers = HeaderMap::new();
+        for value in forwarded {
+            headers.append("x-forwarded-for", HeaderValue::from_static(value));
+        }
+        let mut extensions = Extensions::new();
+        extensions.insert(ConnectInfo(SocketAddr::from(([10, 0, 0, 1], 40000))));
+        (headers, extensions)
+    }
+
+    #[test]
+    fn forwarded_for_is_only_used_when_trusted() {
+        let (headers, extensions) = request_parts(&["203.0.113.7, 198.51.100.2", "192.0.2.9"]);
+        let peer = IpAddr::from([10, 0, 0, 1]);
+
+        assert_eq!(client_ip(&headers, &extensions, None), Some(peer));
+        assert_eq!(client_ip(&headers, &extensions, Some(ForwardedHop::First)), Some(IpAddr::from([203, 0, 113, 7])));
+        assert_eq!(client_ip(&headers, &extensions, Some(ForwardedHop::Last)), Some(IpAddr::from([192, 0, 2, 9])));
+
+        let (garbage, extensions) = request_parts(&["unknown"]);
+        assert_eq!(client_ip(&garbage, &extensions, Some(ForwardedHop::Last)), Some(peer));
+    }
+}
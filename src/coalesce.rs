@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use axum::body::Bytes;
+use axum::http::{HeaderMap, StatusCode};
+use tokio::sync::watch;
+use url::Url;
+
+// A buffered upstream response handed to every request that waited on it.
+pub struct Shared {
+    pub url: Url,
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+// None until the leading fetch is done; Some(None) when its response
+// couldn't be shared and everyone has to fetch for themselves.
+type Outcome = Option<Option<Arc<Shared>>>;
+
+// Single-flight for upstream fetches: the first request for a key fetches,
+// identical requests arriving meanwhile wait for its response instead of
+// going to the origin too.
+#[derive(Default)]
+pub struct Flights {
+    inflight: Mutex<HashMap<String, watch::Receiver<Outcome>>>,
+}
+
+pub enum Role {
+    Leader(Leader),
+    Follower(watch::Receiver<Outcome>),
+}
+
+impl Flights {
+    pub fn join(self: &Arc<Self>, key: String) -> Role {
+        let mut inflight = self.inflight.lock().unwrap();
+        if let Some(flight) = inflight.get(&key) {
+            return Role::Follower(flight.clone());
+        }
+        let (tx, rx) = watch::channel(None);
+        inflight.insert(key.clone(), rx);
+        Role::Leader(Leader { flights: self.clone(), key, tx })
+    }
+}
+
+// Waits for the leader, None meaning there's nothing to share.
+pub async fn follow(mut flight: watch::Receiver<Outcome>) -> Option<Arc<Shared>> {
+    match flight.wait_for(Option::is_some).await {
+        Ok(outcome) => outcome.clone().flatten(),
+        Err(_) => None,
+    }
+}
+
+// The request doing the fetch. Dropping it without `share` lets the
+// followers go ahead on their own.
+pub struct Leader {
+    flights: Arc<Flights>,
+    key: String,
+    tx: watch::Sender<Outcome>,
+}
+
+impl Leader {
+    // Whether any request is waiting yet; the map holds one receiver itself.
+    pub fn has_followers(&self) -> bool {
+        self.tx.receiver_count() > 1
+    }
+
+    pub fn share(self, response: Shared) {
+        self.tx.send_replace(Some(Some(Arc::new(response))));
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        // later requests start a new flight, or find the response in the cache
+        self.flights.inflight.lock().unwrap().remove(&self.key);
+        self.tx.send_if_modified(|outcome| {
+            if outcome.is_some() {
+                return false;
+            }
+            *outcome = Some(None);
+            true
+        });
+    }
+}
+
+// Requests only coalesce when they'd send the origin exactly the same thing,
+// cookies and credentials included.
+pub fn key(url: &str, headers: &reqwest::header::HeaderMap) -> String {
+    let mut headers: Vec<String> = headers
+        .iter()
+        .map(|(name, value)| format!("{name}: {}", String::from_utf8_lossy(value.as_bytes())))
+        .collect();
+    headers.sort();
+    format!("{url}\n{}", headers.join("\n"))
+}
@@ -0,0 +1,363 @@
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use axum::http::{HeaderName, HeaderValue};
+use url::Url;
+
+use crate::allowlist::HostAllowlist;
+use crate::client_ip::ForwardedHop;
+use crate::transform::Transforms;
+
+// Startup settings read from PROXY_* environment variables.
+pub struct Config {
+    // every address served on, from PROXY_BIND entries and PROXY_PORT
+    pub bind: Vec<SocketAddr>,
+    // None means any host may be fetched
+    pub allowed_hosts: Option<HostAllowlist>,
+    // SSRF protection: refuse upstreams resolving to loopback, private and
+    // other non-public addresses (see ssrf::is_blocked_ip). On by default,
+    // PROXY_ALLOW_PRIVATE=1 switches it off for local development and tests.
+    pub block_private_addresses: bool,
+    // byte budget of the in-memory response cache, 0 disables it
+    pub cache_bytes: usize,
+    // how long past expiry a cached response may still be served when the
+    // upstream errors or times out, 0 disables stale-if-error
+    pub stale_if_error: Duration,
+    // extra attempts for connection failures and 502/503/504 upstream responses
+    pub retries: u32,
+    // longest 429 Retry-After waited out, once, before the 429 is passed on;
+    // zero never waits
+    pub max_retry_after: Duration,
+    // let identical concurrent GETs share one upstream fetch
+    pub coalesce: bool,
+    // total upstream request timeout and redirect hop limit of the shared client
+    pub timeout: Duration,
+    // TCP/TLS connect limit, so dead hosts fail long before `timeout`
+    pub connect_timeout: Duration,
+    // how long shutdown waits for open connections before cutting them off
+    pub shutdown_timeout: Duration,
+    pub max_redirects: usize,
+    // browser origins allowed by CORS, None allows any origin
+    pub cors_origins: Option<Vec<HeaderValue>>,
+    // when set, /fetch only serves URLs carrying a valid `sig`
+    pub signing_key: Option<Vec<u8>>,
+    // per-client-IP /fetch requests per second and bucket size, None disables limiting
+    pub rate_limit: Option<f64>,
+    pub rate_burst: Option<f64>,
+    // simultaneous /fetch requests, None means unbounded
+    pub max_concurrency: Option<usize>,
+    // certificate and private key PEM files, serves HTTPS when set
+    pub tls: Option<(PathBuf, PathBuf)>,
+    // http(s):// or socks5:// proxy all upstream traffic goes through,
+    // credentials may be given in the URL
+    pub upstream_proxy: Option<Url>,
+    // largest body proxied for binary content, None means unlimited
+    pub max_body_bytes: Option<u64>,
+    // largest playlist/manifest/subtitle body, these are buffered for rewriting
+    pub max_text_bytes: u64,
+    // lines an HLS playlist may have before it's refused with 413, 0 for no limit
+    pub max_playlist_lines: usize,
+    // longest upstream URL, `b64` ones measured decoded, before a 414; 0 for no limit
+    pub max_url_len: usize,
+    // upstream responses with more headers, or more header bytes, get a 502
+    pub max_upstream_headers: usize,
+    pub max_upstream_header_bytes: usize,
+    // exposes the /info upstream-inspection endpoint, off in production
+    pub info_endpoint: bool,
+    // known-good URL /ready sends a HEAD to, None makes /ready always succeed
+    pub health_url: Option<Url>,
+    // default upstream User-Agent, a `ua` query parameter overrides it
+    pub user_agent: HeaderValue,
+    // leave out the derived Referer / Origin upstream, `no_referer` and
+    // `no_origin` query parameters override these
+    pub no_referer: bool,
+    pub no_origin: bool,
+    // response headers removed before anything is sent to the client
+    pub strip_headers: Vec<HeaderName>,
+    // extra upstream response headers passed through to the client
+    pub copy_headers: Vec<HeaderName>,
+    // deepest playlist nesting through /fetch before answering 508
+    pub max_depth: u32,
+    // speak HTTP/2 to every upstream without negotiating it first
+    pub http2_only: bool,
+    // idle connections kept per upstream host, None keeps reqwest's default
+    pub pool_idle: Option<usize>,
+    // sort query parameters in cache keys, unsafe for origins signing over their order
+    pub sort_query: bool,
+    // take the client IP from X-Forwarded-For, only safe behind a proxy that sets it
+    pub trust_forwarded: Option<ForwardedHop>,
+    // Cache-Control and CDN-Cache-Control sent for playlists/manifests and for
+    // everything else, replacing upstream and default values when set
+    pub playlist_cache_control: Option<String>,
+    pub segment_cache_control: Option<String>,
+    // per-origin URL tweaks applied before fetching
+    pub url_transforms: Transforms,
+    // file one JSON line per request is appended to, None disables the access log
+    pub access_log: Option<PathBuf>,
+    // lowercase Content-Type prefixes upstream responses must match, None allows any
+    pub allowed_content_types: Option<Vec<String>>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind: vec![SocketAddr::from(([0, 0, 0, 0], 3000))],
+            allowed_hosts: None,
+            block_private_addresses: true,
+            cache_bytes: 0,
+            stale_if_error: Duration::from_secs(30),
+            retries: 2,
+            max_retry_after: Duration::from_secs(2),
+            coalesce: true,
+            timeout: Duration::from_secs(15),
+            connect_timeout: Duration::from_secs(5),
+            shutdown_timeout: Duration::from_secs(30),
+            max_redirects: 5,
+            cors_origins: None,
+            signing_key: None,
+            rate_limit: None,
+            rate_burst: None,
+            max_concurrency: None,
+            tls: None,
+            upstream_proxy: None,
+            max_body_bytes: None,
+            max_text_bytes: 10 * 1024 * 1024,
+            max_playlist_lines: 200_000,
+            max_url_len: 8192,
+            max_upstream_headers: 100,
+            max_upstream_header_bytes: 64 * 1024,
+            info_endpoint: false,
+            health_url: None,
+            user_agent: HeaderValue::from_static("Mozilla/5.0 (compatible; RustProxy/1.0)"),
+            no_referer: false,
+            no_origin: false,
+            strip_headers: Vec::new(),
+            copy_headers: Vec::new(),
+            max_depth: 8,
+            http2_only: false,
+            pool_idle: None,
+            sort_query: false,
+            trust_forwarded: None,
+            playlist_cache_control: None,
+            segment_cache_control: None,
+            url_transforms: Transforms::default(),
+            access_log: None,
+            allowed_content_types: None,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Result<Self, String> {
+        let defaults = Config::default();
+        Ok(Config {
+            bind: bind_addresses()?,
+            allowed_hosts: env_var("PROXY_ALLOWED_HOSTS").map(|v| HostAllowlist::parse(&v)),
+            block_private_addresses: !parse_flag("PROXY_ALLOW_PRIVATE", false)?,
+            cache_bytes: parse_env("PROXY_CACHE_BYTES", defaults.cache_bytes)?,
+            stale_if_error: Duration::from_secs(parse_env("PROXY_STALE_IF_ERROR_SECS", defaults.stale_if_error.as_secs())?),
+            retries: parse_env("PROXY_RETRIES", defaults.retries)?,
+            max_retry_after: Duration::from_secs(parse_env("PROXY_MAX_RETRY_AFTER_SECS", defaults.max_retry_after.as_secs())?),
+            coalesce: parse_flag("PROXY_COALESCE", defaults.coalesce)?,
+            timeout: Duration::from_secs(parse_env("PROXY_TIMEOUT_SECS", defaults.timeout.as_secs())?),
+            connect_timeout: Duration::from_secs(parse_env("PROXY_CONNECT_TIMEOUT_SECS", defaults.connect_timeout.as_secs())?),
+            shutdown_timeout: Duration::from_secs(parse_env("PROXY_SHUTDOWN_TIMEOUT_SECS", defaults.shutdown_timeout.as_secs())?),
+            max_redirects: parse_env("PROXY_MAX_REDIRECTS", defaults.max_redirects)?,
+            cors_origins: env_var("PROXY_CORS_ORIGINS").map(|v| parse_list(&v)).transpose()?,
+            signing_key: env_var("PROXY_SIGNING_KEY").map(String::into_bytes),
+            rate_limit: parse_optional_env("PROXY_RATE_LIMIT")?.filter(|rate: &f64| *rate > 0.0),
+            rate_burst: parse_optional_env("PROXY_RATE_BURST")?,
+            max_concurrency: parse_optional_env("PROXY_MAX_CONCURRENCY")?,
+            tls: tls_paths()?,
+            upstream_proxy: upstream_proxy()?,
+            max_body_bytes: parse_optional_env("PROXY_MAX_BODY_BYTES")?,
+            max_text_bytes: parse_env("PROXY_MAX_TEXT_BYTES", defaults.max_text_bytes)?,
+            max_playlist_lines: parse_env("PROXY_MAX_PLAYLIST_LINES", defaults.max_playlist_lines)?,
+            max_url_len: parse_env("PROXY_MAX_URL_LEN", defaults.max_url_len)?,
+            max_upstream_headers: parse_env("PROXY_MAX_UPSTREAM_HEADERS", defaults.max_upstream_headers)?,
+            max_upstream_header_bytes: parse_env("PROXY_MAX_UPSTREAM_HEADER_BYTES", defaults.max_upstream_header_bytes)?,
+            info_endpoint: parse_flag("PROXY_ENABLE_INFO", defaults.info_endpoint)?,
+            health_url: parse_optional_env("PROXY_HEALTH_URL")?,
+            user_agent: env_var("PROXY_USER_AGENT")
+                .map(|v| HeaderValue::from_str(v.trim()).map_err(|e| format!("invalid PROXY_USER_AGENT value {v:?}: {e}")))
+                .transpose()?
+                .unwrap_or(defaults.user_agent),
+            no_referer: parse_flag("PROXY_NO_REFERER", defaults.no_referer)?,
+            no_origin: parse_flag("PROXY_NO_ORIGIN", defaults.no_origin)?,
+            strip_headers: env_var("PROXY_STRIP_HEADERS").map(|v| parse_header_names(&v)).transpose()?.unwrap_or_default(),
+            copy_headers: env_var("PROXY_COPY_HEADERS").map(|v| parse_header_names(&v)).transpose()?.unwrap_or_default(),
+            max_depth: parse_env("PROXY_MAX_DEPTH", defaults.max_depth)?,
+            http2_only: parse_flag("PROXY_HTTP2_ONLY", defaults.http2_only)?,
+            pool_idle: parse_optional_env("PROXY_POOL_IDLE")?,
+            sort_query: parse_flag("PROXY_SORT_QUERY", defaults.sort_query)?,
+            trust_forwarded: trust_forwarded()?,
+            playlist_cache_control: header_value_env("PROXY_M3U8_CACHE")?,
+            segment_cache_control: header_value_env("PROXY_SEGMENT_CACHE")?,
+            url_transforms: env_var("PROXY_URL_TRANSFORMS").map(|v| Transforms::parse(&v)).transpose()?.unwrap_or_default(),
+            access_log: access_log_path()?,
+            allowed_content_types: allowed_content_types()?,
+        })
+    }
+}
+
+fn tls_paths() -> Result<Option<(PathBuf, PathBuf)>, String> {
+    let (cert, key) = match (env_var("PROXY_TLS_CERT"), env_var("PROXY_TLS_KEY")) {
+        (Some(cert), Some(key)) => (PathBuf::from(cert), PathBuf::from(key)),
+        (None, None) => return Ok(None),
+        _ => return Err("PROXY_TLS_CERT and PROXY_TLS_KEY must be set together".to_string()),
+    };
+    for path in [&cert, &key] {
+        if !path.is_file() {
+            return Err(format!("TLS file {} does not exist", path.display()));
+        }
+    }
+    Ok(Some((cert, key)))
+}
+
+fn allowed_content_types() -> Result<Option<Vec<String>>, String> {
+    let Some(value) = env_var("PROXY_ALLOWED_CONTENT_TYPES") else {
+        return Ok(None);
+    };
+    let prefixes: Vec<String> = value
+        .split(',')
+        .map(|v| v.trim().to_ascii_lowercase())
+        .filter(|v| !v.is_empty())
+        .collect();
+    if prefixes.is_empty() {
+        return Err("PROXY_ALLOWED_CONTENT_TYPES lists no content types".to_string());
+    }
+    Ok(Some(prefixes))
+}
+
+fn access_log_path() -> Result<Option<PathBuf>, String> {
+    let Some(value) = env_var("PROXY_ACCESS_LOG") else {
+        return Ok(None);
+    };
+    let path = PathBuf::from(value.trim());
+    // fail at startup rather than losing every line later
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .map_err(|e| format!("cannot open PROXY_ACCESS_LOG file {}: {e}", path.display()))?;
+    Ok(Some(path))
+}
+
+// PROXY_TRUST_FORWARDED turns it on, PROXY_FORWARDED_HOP=first|last (default
+// last) picks the X-Forwarded-For entry.
+fn trust_forwarded() -> Result<Option<ForwardedHop>, String> {
+    if !parse_flag("PROXY_TRUST_FORWARDED", false)? {
+        return Ok(None);
+    }
+    match env_var("PROXY_FORWARDED_HOP").map(|v| v.trim().to_ascii_lowercase()).as_deref() {
+        None | Some("last") => Ok(Some(ForwardedHop::Last)),
+        Some("first") => Ok(Some(ForwardedHop::First)),
+        Some(other) => Err(format!("invalid PROXY_FORWARDED_HOP value {other:?}: expected first or last")),
+    }
+}
+
+// PROXY_BIND is a comma-separated list of IPs, each served on PROXY_PORT, or
+// of `ip:port` / `[ipv6]:port` entries with their own port.
+fn bind_addresses() -> Result<Vec<SocketAddr>, String> {
+    let port = parse_env("PROXY_PORT", 3000u16)?;
+    let Some(value) = env_var("PROXY_BIND") else {
+        return Ok(vec![SocketAddr::from(([0, 0, 0, 0], port))]);
+    };
+    let addrs = value
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(|v| {
+            v.parse::<SocketAddr>()
+                .or_else(|_| v.parse::<IpAddr>().map(|ip| SocketAddr::new(ip, port)))
+                .map_err(|_| format!("invalid PROXY_BIND entry {v:?}: expected an IP address or address:port"))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    if addrs.is_empty() {
+        return Err("PROXY_BIND lists no addresses".to_string());
+    }
+    Ok(addrs)
+}
+
+fn upstream_proxy() -> Result<Option<Url>, String> {
+    let Some(value) = env_var("PROXY_UPSTREAM") else {
+        return Ok(None);
+    };
+    let url = Url::parse(value.trim()).map_err(|e| format!("invalid PROXY_UPSTREAM value {value:?}: {e}"))?;
+    if !matches!(url.scheme(), "http" | "https" | "socks5" | "socks5h") {
+        return Err(format!(
+            "invalid PROXY_UPSTREAM value {value:?}: scheme must be http, https, socks5 or socks5h"
+        ));
+    }
+    // make sure reqwest accepts it too, before we try to build the client
+    reqwest::Proxy::all(url.as_str()).map_err(|e| format!("invalid PROXY_UPSTREAM value {value:?}: {e}"))?;
+    Ok(Some(url))
+}
+
+// Unset and empty variables are treated the same.
+fn env_var(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|v| !v.trim().is_empty())
+}
+
+fn parse_env<T: FromStr>(name: &str, default: T) -> Result<T, String>
+where
+    T::Err: std::fmt::Display,
+{
+    Ok(parse_optional_env(name)?.unwrap_or(default))
+}
+
+fn parse_optional_env<T: FromStr>(name: &str) -> Result<Option<T>, String>
+where
+    T::Err: std::fmt::Display,
+{
+    env_var(name)
+        .map(|value| {
+            value
+                .trim()
+                .parse()
+                .map_err(|e| format!("invalid {name} value {value:?}: {e}"))
+        })
+        .transpose()
+}
+
+// A variable used verbatim as a response header value.
+fn header_value_env(name: &str) -> Result<Option<String>, String> {
+    env_var(name)
+        .map(|value| {
+            let value = value.trim().to_string();
+            HeaderValue::from_str(&value).map_err(|e| format!("invalid {name} value {value:?}: {e}"))?;
+            Ok(value)
+        })
+        .transpose()
+}
+
+fn parse_flag(name: &str, default: bool) -> Result<bool, String> {
+    match env_var(name).map(|v| v.trim().to_ascii_lowercase()) {
+        Some(v) if matches!(v.as_str(), "1" | "true" | "yes" | "on") => Ok(true),
+        Some(v) if matches!(v.as_str(), "0" | "false" | "no" | "off") => Ok(false),
+        Some(v) => Err(format!("invalid {name} value {v:?}: expected a boolean")),
+        None => Ok(default),
+    }
+}
+
+// Comma-separated header values, empty entries skipped.
+fn parse_list(value: &str) -> Result<Vec<HeaderValue>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(|v| HeaderValue::from_str(v).map_err(|_| format!("invalid header value {v:?}")))
+        .collect()
+}
+
+// Comma-separated header names, case-insensitive, empty entries skipped.
+fn parse_header_names(value: &str) -> Result<Vec<HeaderName>, String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(|v| HeaderName::from_bytes(v.as_bytes()).map_err(|_| format!("invalid header name {v:?}")))
+        .collect()
+}
@@ -0,0 +1,163 @@
+use quick_xml::events::{BytesStart, BytesText, Event};
+use quick_xml::{Reader, Writer};
+use url::Url;
+
+use crate::links::Links;
+
+// (element, attribute) pairs that carry segment URLs
+const URL_ATTRIBUTES: &[(&[u8], &[u8])] = &[
+    (b"SegmentTemplate", b"media"),
+    (b"SegmentTemplate", b"initialization"),
+    (b"SegmentTemplate", b"index"),
+    (b"SegmentURL", b"media"),
+    (b"SegmentURL", b"index"),
+    (b"Initialization", b"sourceURL"),
+    (b"RepresentationIndex", b"sourceURL"),
+];
+
+// Rewrites every BaseURL and segment URL attribute of an MPD manifest to go
+// through /fetch. Relative URLs are resolved against the manifest location and
+// any enclosing BaseURL, everything else in the document is written back as-is.
+pub fn rewrite_manifest(xml: &str, manifest_url: &Url, links: &Links) -> Result<String, quick_xml::Error> {
+    let mut reader = Reader::from_str(xml);
+    let mut writer = Writer::new(Vec::new());
+
+    // base URL in effect inside each open element, innermost last
+    let mut bases = vec![manifest_url.clone()];
+    let mut in_base_url = false;
+
+    loop {
+        match reader.read_event()? {
+            Event::Eof => break,
+            Event::Start(e) => {
+                let base = bases.last().unwrap_or(manifest_url).clone();
+                in_base_url = e.local_name().as_ref() == b"BaseURL";
+                let rewritten = rewrite_attributes(&e, &base, links)?;
+                bases.push(base);
+                writer.write_event(Event::Start(rewritten))?;
+            }
+            Event::Empty(e) => {
+                let base = bases.last().unwrap_or(manifest_url);
+                writer.write_event(Event::Empty(rewrite_attributes(&e, base, links)?))?;
+            }
+            Event::End(e) => {
+                in_base_url = false;
+                bases.pop();
+                writer.write_event(Event::End(e))?;
+            }
+            Event::Text(t) if in_base_url => {
+                let text = t.unescape()?;
+                // a BaseURL applies to its parent element, so update the parent's base
+                let parent = bases.len().saturating_sub(2);
+                match bases[parent].join(text.trim()) {
+                    Ok(resolved) => {
+                        let link = links.template_link(&resolved);
+                        bases[parent] = resolved;
+                        writer.write_event(Event::Text(BytesText::new(&link)))?;
+                    }
+                    Err(_) => writer.write_event(Event::Text(t))?,
+                }
+            }
+            event => writer.write_event(event)?,
+        }
+    }
+
+    Ok(String::from_utf8_lossy(&writer.into_inner()).into_owned())
+}
+
+fn rewrite_attributes(
+    element: &BytesStart,
+    base: &Url,
+    links: &Links,
+) -> Result<BytesStart<'static>, quick_xml::Error> {
+    let local_name = element.local_name();
+    let name = String::from_utf8_lossy(element.name().as_ref()).into_owned();
+    let mut rewritten = BytesStart::new(name);
+
+    for attr in element.attributes() {
+        let attr = attr?;
+        let is_url = URL_ATTRIBUTES
+            .iter()
+            .any(|(el, key)| *el == local_name.as_ref() && *key == attr.key.local_name().as_ref());
+        let resolved = if is_url {
+            base.join(&attr.unescape_value()?).ok()
+        } else {
+            None
+        };
+        match resolved {
+            Some(resolved) => {
+                let key = String::from_utf8_lossy(attr.key.as_ref()).into_owned();
+                rewritten.push_attribute((key.as_str(), links.template_link(&resolved).as_str()));
+            }
+            None => rewritten.push_attribute(attr),
+        }
+    }
+
+    Ok(rewritten.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST_URL: &str = "https://cdn.example.com/dash/stream.mpd";
+
+    fn rewrite(xml: &str) -> String {
+        rewrite_manifest(xml, &Url::parse(MANIFEST_URL).unwrap(), &Links::default()).unwrap()
+    }
+
+    // Value of the first `name="..."` attribute in `xml`.
+    fn attribute<'x>(xml: &'x str, name: &str) -> &'x str {
+        let start = xml.find(&format!(" {name}=\"")).expect("attribute missing") + name.len() + 3;
+        &xml[start..start + xml[start..].find('"').unwrap()]
+    }
+
+    #[test]
+    fn format_tagged_identifiers_stay_unescaped() {
+        let xml = r#"<MPD><Period><AdaptationSet><SegmentTemplate media="seg-$Number%05d$.m4s"/></AdaptationSet></Period></MPD>"#;
+        let media = attribute(&rewrite(xml), "media").to_string();
+        assert_eq!(media, "/fetch?url=https%3A%2F%2Fcdn.example.com%2Fdash%2Fseg-$Number%05d$.m4s");
+    }
+
+    // Upstream URL behind a rewritten /fetch?url= link, template tokens included.
+    fn link_target(link: &str) -> String {
+        let query = link.strip_prefix("/fetch?url=").expect("not a proxied link");
+        urlencoding::decode(query.split('&').next().unwrap()).unwrap().into_owned()
+    }
+
+    #[test]
+    fn base_url_and_templates_are_rewritten() {
+        let xml = concat!(
+            r#"<MPD mediaPresentationDuration="PT30S"><BaseURL>video/</BaseURL><Period id="p0">"#,
+            r#"<AdaptationSet mimeType="video/mp4"><SegmentTemplate timescale="1000" duration="2000" "#,
+            r#"media="$RepresentationID$/$Number$.m4s" initialization="$RepresentationID$/init.mp4"/>"#,
+            r#"<Representation id="720p" bandwidth="3000000"/></AdaptationSet></Period></MPD>"#,
+        );
+        let out = rewrite(xml);
+
+        let base = &out[out.find("<BaseURL>").unwrap() + 9..out.find("</BaseURL>").unwrap()];
+        assert_eq!(link_target(base), "https://cdn.example.com/dash/video/");
+        // relative templates resolve against the enclosing BaseURL
+        assert_eq!(link_target(attribute(&out, "media")), "https://cdn.example.com/dash/video/$RepresentationID$/$Number$.m4s");
+        assert_eq!(link_target(attribute(&out, "initialization")), "https://cdn.example.com/dash/video/$RepresentationID$/init.mp4");
+        assert!(attribute(&out, "media").contains("$RepresentationID$%2F$Number$"));
+    }
+
+    #[test]
+    fn absolute_segment_urls_are_rewritten() {
+        let xml = r#"<MPD><Period><SegmentList><Initialization sourceURL="https://other.example.net/init.mp4"/><SegmentURL media="https://other.example.net/1.m4s"/></SegmentList></Period></MPD>"#;
+        let out = rewrite(xml);
+        assert_eq!(link_target(attribute(&out, "sourceURL")), "https://other.example.net/init.mp4");
+        assert_eq!(link_target(attribute(&out, "media")), "https://other.example.net/1.m4s");
+    }
+
+    #[test]
+    fn other_attributes_pass_through() {
+        let xml = concat!(
+            r#"<MPD type="static" profiles="urn:mpeg:dash:profile:isoff-live:2011"><Period id="p0" start="PT0S">"#,
+            r#"<AdaptationSet mimeType="video/mp4" codecs="avc1.4d401f"><Representation id="720p" bandwidth="3000000" width="1280"/>"#,
+            r#"</AdaptationSet></Period></MPD>"#,
+        );
+        assert_eq!(rewrite(xml), xml);
+    }
+}
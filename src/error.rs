@@ -0,0 +1,64 @@
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Serialize;
+
+// An error the proxy answers with itself, as opposed to an upstream status
+// passed through. Rendered as plain text unless the client asked for JSON.
+#[derive(Debug)]
+pub struct ProxyError {
+    status: StatusCode,
+    code: &'static str,
+    message: String,
+}
+
+impl ProxyError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self { status, code, message: message.into() }
+    }
+
+    pub fn body_assembly() -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, "BODY_ASSEMBLY_FAILED", "Body assembly failed")
+    }
+}
+
+// Stashed in the response extensions so `negotiate` can re-render the error.
+#[derive(Clone, Serialize)]
+struct ErrorBody {
+    error: String,
+    code: &'static str,
+}
+
+impl IntoResponse for ProxyError {
+    fn into_response(self) -> Response {
+        let mut res = (self.status, self.message.clone()).into_response();
+        res.extensions_mut().insert(ErrorBody { error: self.message, code: self.code });
+        res
+    }
+}
+
+// Middleware turning `ProxyError` responses into `{ "error", "code" }` JSON
+// for clients sending `Accept: application/json`. Other headers, such as
+// Retry-After, are kept.
+pub async fn negotiate(request: Request, next: Next) -> Response {
+    let wants_json = request
+        .headers()
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|accept| accept.contains("application/json"));
+
+    let mut res = next.run(request).await;
+    if let Some(body) = res.extensions_mut().remove::<ErrorBody>()
+        && wants_json
+        && let Ok(json) = serde_json::to_string(&body)
+    {
+        res.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        res.headers_mut().remove(header::CONTENT_LENGTH);
+        *res.body_mut() = Body::from(json);
+    }
+    res
+}
@@ -0,0 +1,403 @@
+use url::Url;
+
+use crate::links::Links;
+
+// Whether a playlist lists variant streams or the segments of one stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaylistKind {
+    Master,
+    Media,
+}
+
+// Classifies a playlist by its tags; None when it has neither
+// #EXT-X-STREAM-INF nor #EXTINF, e.g. an empty live window.
+pub fn playlist_kind(text: &str) -> Option<PlaylistKind> {
+    let mut kind = None;
+    for line in text.lines() {
+        if line.starts_with("#EXT-X-STREAM-INF") {
+            // a master playlist never carries segments, so this wins
+            return Some(PlaylistKind::Master);
+        }
+        if line.starts_with("#EXTINF") {
+            kind = Some(PlaylistKind::Media);
+        }
+    }
+    kind
+}
+
+// Whether a body is an HLS playlist whatever its Content-Type claims:
+// playlists must open with #EXTM3U, after an optional BOM.
+pub fn looks_like_playlist(body: &[u8]) -> bool {
+    let body = body.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(body);
+    body.trim_ascii_start().starts_with(b"#EXTM3U")
+}
+
+// A media playlist the origin is still appending segments to, i.e. one
+// without #EXT-X-ENDLIST. Master playlists never count as live.
+pub fn is_live(text: &str) -> bool {
+    playlist_kind(text) == Some(PlaylistKind::Media)
+        && !text.lines().any(|line| line.trim_end() == "#EXT-X-ENDLIST")
+}
+
+// Replaces any #EXT-X-START tag with one starting playback `offset` seconds
+// in, placed right after the #EXTM3U header. A BOM or blank lines ahead of
+// the header are dropped, as looks_like_playlist allows them.
+pub fn set_start(text: &str, offset: f64) -> String {
+    let text = text.strip_prefix('\u{feff}').unwrap_or(text).trim_start();
+    let mut lines = text.lines().filter(|line| !line.starts_with("#EXT-X-START"));
+    let tag = format!("#EXT-X-START:TIME-OFFSET={offset}");
+    let mut out = Vec::new();
+    match lines.next() {
+        Some(header) if header.trim_end() == "#EXTM3U" => out.extend([header, &tag]),
+        // no header to put it after
+        first => {
+            out.push(&tag);
+            out.extend(first);
+        }
+    }
+    out.extend(lines);
+    out.join("\n")
+}
+
+// Rewrites every URI in an HLS playlist to go through /fetch. Relative URIs
+// are resolved against `base`, the playlist's own URL, and the resolved
+// absolute URL is encoded into the link exactly once. URI lines are variant
+// playlists in a master playlist and segments in a media playlist, both are
+// proxied the same way; tag lines only change where they carry URI="...".
+// Playlists longer than `max_lines` are refused before they're rewritten
+// in full.
+pub fn rewrite_playlist(
+    text: &str,
+    base: &Url,
+    links: &Links,
+    max_lines: Option<usize>,
+) -> Result<String, TooManyLines> {
+    text.lines()
+        .enumerate()
+        .map(|(n, line)| {
+            if max_lines.is_some_and(|max| n >= max) {
+                return Err(TooManyLines);
+            }
+            Ok(rewrite_line(line, base, links))
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(|lines| lines.join("\n"))
+}
+
+#[derive(Debug)]
+pub struct TooManyLines;
+
+// One playlist line, see rewrite_playlist.
+fn rewrite_line(line: &str, base: &Url, links: &Links) -> String {
+    // tags carrying a URI="..." attribute (keys and master-playlist session
+    // keys, fMP4 init segments, alternate audio/subtitle renditions,
+    // trick-play I-frame playlists)
+    if line.starts_with("#EXT-X-KEY") || line.starts_with("#EXT-X-SESSION-KEY") {
+        // key servers, often on another host, tend to check for the
+        // playlist's origin as Referer
+        let referer = base.origin().ascii_serialization();
+        return rewrite_attribute(line, "URI", base, |target| links.link_with_referer(target, &referer));
+    }
+    if line.starts_with("#EXT-X-MAP")
+        || line.starts_with("#EXT-X-MEDIA:")
+        || line.starts_with("#EXT-X-I-FRAME-STREAM-INF")
+    {
+        return rewrite_attribute(line, "URI", base, |target| links.link(target));
+    }
+    // ad insertion markers pointing at the ad creative
+    if line.starts_with("#EXT-X-DATERANGE") {
+        return rewrite_attribute(line, "X-ASSET-URI", base, |target| links.link(target));
+    }
+    if line.starts_with("#") || line.trim().is_empty() {
+        return line.to_string();
+    }
+    match base.join(line.trim()) {
+        Ok(resolved) => links.pinned_link(&resolved),
+        Err(_) => line.to_string(),
+    }
+}
+
+// Rewrites the `name="..."` attribute of a playlist tag to go through /fetch,
+// leaving every other attribute (METHOD, IV, BYTERANGE, ...) untouched.
+fn rewrite_attribute(line: &str, name: &str, base: &Url, link: impl Fn(&Url) -> String) -> String {
+    let Some(uri_start) = find_attribute(line, name) else {
+        return line.to_string();
+    };
+    let uri_end = line[uri_start..]
+        .find('"')
+        .map(|e| e + uri_start)
+        .unwrap_or(line.len());
+    let uri = &line[uri_start..uri_end];
+    match base.join(uri) {
+        // inline keys (`data:text/plain;base64,...`) are already in the
+        // playlist, there's nothing to fetch
+        Ok(resolved) if resolved.scheme() != "data" => format!("{}{}{}", &line[..uri_start], link(&resolved), &line[uri_end..]),
+        _ => line.to_string(),
+    }
+}
+
+// Start of the quoted value of attribute `name`. Only whole attribute names
+// match, so looking for URI skips X-ASSET-URI.
+fn find_attribute(line: &str, name: &str) -> Option<usize> {
+    let needle = format!("{name}=\"");
+    let mut from = 0;
+    while let Some(found) = line[from..].find(&needle) {
+        let start = from + found;
+        if line[..start].ends_with([':', ',']) {
+            return Some(start + needle.len());
+        }
+        from = start + needle.len();
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::links::decode_b64_url;
+
+    // Upstream URL a rewritten /fetch link points at.
+    fn link_target(link: &str) -> String {
+        let query = link.strip_prefix("/fetch?b64=").expect("not a proxied link");
+        let b64 = query.split('&').next().unwrap();
+        decode_b64_url(b64).expect("invalid b64")
+    }
+
+    fn rewrite_segment(line: &str) -> String {
+        let base = Url::parse("https://cdn.example.com/hls/720p/index.m3u8").unwrap();
+        link_target(&rewrite_playlist(line, &base, &Links::default(), None).unwrap())
+    }
+
+    #[test]
+    fn segment_query_string_survives() {
+        assert_eq!(
+            rewrite_segment("chunk-1.ts?token=abc&exp=123"),
+            "https://cdn.example.com/hls/720p/chunk-1.ts?token=abc&exp=123"
+        );
+    }
+
+    #[test]
+    fn segment_fragment_survives() {
+        assert_eq!(
+            rewrite_segment("chunk-1.ts#t=10"),
+            "https://cdn.example.com/hls/720p/chunk-1.ts#t=10"
+        );
+    }
+
+    #[test]
+    fn pre_encoded_segment_is_not_encoded_again() {
+        assert_eq!(
+            rewrite_segment("chunk%201%2Fa.ts?sig=a%2Bb%3D"),
+            "https://cdn.example.com/hls/720p/chunk%201%2Fa.ts?sig=a%2Bb%3D"
+        );
+    }
+
+    #[test]
+    fn absolute_segment_on_other_host() {
+        assert_eq!(
+            rewrite_segment("https://other.example.net/x/seg.ts?a=1"),
+            "https://other.example.net/x/seg.ts?a=1"
+        );
+    }
+
+    const MASTER: &str = include_str!("../tests/fixtures/hls/master.m3u8");
+    const MEDIA: &str = include_str!("../tests/fixtures/hls/media.m3u8");
+
+    // (rewritten line, original line) pairs of a fixture
+    fn rewrite_fixture<'a>(text: &'a str, base: &str) -> Vec<(String, &'a str)> {
+        let base = Url::parse(base).unwrap();
+        let rewritten = rewrite_playlist(text, &base, &Links::default(), None).unwrap();
+        rewritten.lines().map(str::to_string).zip(text.lines()).collect()
+    }
+
+    #[test]
+    fn playlist_kinds_are_detected() {
+        assert_eq!(playlist_kind(MASTER), Some(PlaylistKind::Master));
+        assert_eq!(playlist_kind(MEDIA), Some(PlaylistKind::Media));
+        assert_eq!(playlist_kind("#EXTM3U\n#EXT-X-TARGETDURATION:6\n"), None);
+    }
+
+    #[test]
+    fn live_playlists_are_detected() {
+        assert!(!is_live(MEDIA));
+        assert!(!is_live(MASTER));
+        assert!(is_live("#EXTM3U\n#EXT-X-MEDIA-SEQUENCE:42\n#EXTINF:6,\nseg-42.ts\n"));
+    }
+
+    #[test]
+    fn master_playlist_variants_are_rewritten() {
+        let lines = rewrite_fixture(MASTER, "https://cdn.example.com/vod/master.m3u8");
+
+        let variants: Vec<_> = lines
+            .iter()
+            .filter(|(_, original)| !original.starts_with('#'))
+            .map(|(rewritten, _)| link_target(rewritten))
+            .collect();
+        assert_eq!(
+            variants,
+            [
+                "https://cdn.example.com/vod/720p/index.m3u8",
+                "https://edge.example.net/vod/360p/index.m3u8?token=abc",
+            ]
+        );
+        // STREAM-INF attributes, quoted commas included, stay as they are
+        for (rewritten, original) in &lines {
+            if original.starts_with("#EXT-X-STREAM-INF") || original.starts_with("#EXT-X-VERSION") {
+                assert_eq!(rewritten, original);
+            }
+        }
+        assert!(lines[3].0.contains("URI=\"/fetch?b64="));
+    }
+
+    #[test]
+    fn media_playlist_segments_are_rewritten() {
+        let lines = rewrite_fixture(MEDIA, "https://cdn.example.com/vod/720p/index.m3u8");
+
+        let segments: Vec<_> = lines
+            .iter()
+            .filter(|(_, original)| !original.starts_with('#'))
+            .map(|(rewritten, _)| link_target(rewritten))
+            .collect();
+        assert_eq!(
+            segments,
+            [
+                "https://cdn.example.com/vod/720p/seg-0.ts",
+                "https://cdn.example.com/vod/720p/seg-1.ts?exp=1700000000&sig=a%2Bb",
+                "https://ads.example.org/break/ad-0.ts",
+            ]
+        );
+        for (rewritten, original) in &lines {
+            if original.starts_with("#EXTINF") || original.starts_with("#EXT-X-DISCONTINUITY") {
+                assert_eq!(rewritten, original);
+            }
+        }
+    }
+
+    #[test]
+    fn key_uri_keeps_other_attributes() {
+        let base = Url::parse("https://cdn.example.com/hls/index.m3u8").unwrap();
+        let line = r#"#EXT-X-KEY:METHOD=AES-128,URI="key.bin?k=1",IV=0x1234"#;
+        let rewritten = rewrite_playlist(line, &base, &Links::default(), None).unwrap();
+
+        let (prefix, rest) = rewritten.split_once("URI=\"").unwrap();
+        let (link, suffix) = rest.split_once('"').unwrap();
+        assert_eq!(prefix, "#EXT-X-KEY:METHOD=AES-128,");
+        assert_eq!(suffix, ",IV=0x1234");
+        assert_eq!(link_target(link), "https://cdn.example.com/hls/key.bin?k=1");
+    }
+
+    #[test]
+    fn iframe_playlist_uri_is_rewritten() {
+        let base = Url::parse("https://cdn.example.com/vod/master.m3u8").unwrap();
+        let line = r#"#EXT-X-I-FRAME-STREAM-INF:BANDWIDTH=86000,CODECS="avc1.4d001f",URI="720p/iframe.m3u8""#;
+        let rewritten = rewrite_playlist(line, &base, &Links::default(), None).unwrap();
+
+        let (prefix, rest) = rewritten.split_once("URI=\"").unwrap();
+        assert_eq!(prefix, r#"#EXT-X-I-FRAME-STREAM-INF:BANDWIDTH=86000,CODECS="avc1.4d001f","#);
+        assert_eq!(link_target(rest.trim_end_matches('"')), "https://cdn.example.com/vod/720p/iframe.m3u8");
+    }
+
+    #[test]
+    fn daterange_asset_uri_is_rewritten() {
+        let base = Url::parse("https://cdn.example.com/live/index.m3u8").unwrap();
+        let line = concat!(
+            r#"#EXT-X-DATERANGE:ID="ad-1",CLASS="com.apple.hls.interstitial","#,
+            r#"START-DATE="2024-01-01T00:00:00Z",DURATION=15.0,X-ASSET-URI="ads/spot.m3u8""#,
+        );
+        let rewritten = rewrite_playlist(line, &base, &Links::default(), None).unwrap();
+
+        let (prefix, rest) = rewritten.split_once("X-ASSET-URI=\"").unwrap();
+        assert_eq!(prefix, r#"#EXT-X-DATERANGE:ID="ad-1",CLASS="com.apple.hls.interstitial",START-DATE="2024-01-01T00:00:00Z",DURATION=15.0,"#);
+        assert_eq!(link_target(rest.trim_end_matches('"')), "https://cdn.example.com/live/ads/spot.m3u8");
+
+        let plain = r#"#EXT-X-DATERANGE:ID="splice-7",START-DATE="2024-01-01T00:00:00Z",SCTE35-OUT=0xFC30"#;
+        assert_eq!(rewrite_playlist(plain, &base, &Links::default(), None).unwrap(), plain);
+    }
+
+    #[test]
+    fn key_on_other_host_carries_playlist_origin_as_referer() {
+        let base = Url::parse("https://cdn.example.com/hls/index.m3u8").unwrap();
+        let line = r#"#EXT-X-KEY:METHOD=AES-128,URI="https://keys.example.org/k/1""#;
+        let rewritten = rewrite_playlist(line, &base, &Links::default(), None).unwrap();
+
+        let link = rewritten.split('"').nth(1).unwrap();
+        assert_eq!(link_target(link), "https://keys.example.org/k/1");
+        assert!(link.ends_with("&ref_=https%3A%2F%2Fcdn.example.com"), "{link}");
+    }
+
+    #[test]
+    fn inline_data_key_is_left_alone() {
+        let base = Url::parse("https://cdn.example.com/hls/index.m3u8").unwrap();
+        let playlist = concat!(
+            "#EXTM3U\n",
+            "#EXT-X-KEY:METHOD=AES-128,URI=\"data:text/plain;base64,AAECAwQFBgcICQoLDA0ODw==\",IV=0x1234\n",
+            "#EXTINF:6,\n",
+            "seg-0.ts",
+        );
+        let rewritten = rewrite_playlist(playlist, &base, &Links::default(), None).unwrap();
+        let lines: Vec<&str> = rewritten.lines().collect();
+        assert_eq!(lines[1], playlist.lines().nth(1).unwrap());
+        assert_eq!(link_target(lines[3]), "https://cdn.example.com/hls/seg-0.ts");
+    }
+
+    #[test]
+    fn session_key_is_rewritten_like_key() {
+        let base = Url::parse("https://cdn.example.com/hls/master.m3u8").unwrap();
+        let line = r#"#EXT-X-SESSION-KEY:METHOD=AES-128,URI="https://keys.example.org/k/1",IV=0x1234"#;
+        let rewritten = rewrite_playlist(line, &base, &Links::default(), None).unwrap();
+
+        let (prefix, rest) = rewritten.split_once("URI=\"").unwrap();
+        let (link, suffix) = rest.split_once('"').unwrap();
+        assert_eq!(prefix, "#EXT-X-SESSION-KEY:METHOD=AES-128,");
+        assert_eq!(suffix, ",IV=0x1234");
+        assert_eq!(link_target(link), "https://keys.example.org/k/1");
+        assert!(link.ends_with("&ref_=https%3A%2F%2Fcdn.example.com"), "{link}");
+
+        let inline = r#"#EXT-X-SESSION-KEY:METHOD=AES-128,URI="data:text/plain;base64,AAECAwQFBgcICQoLDA0ODw==""#;
+        assert_eq!(rewrite_playlist(inline, &base, &Links::default(), None).unwrap(), inline);
+    }
+
+    #[test]
+    fn playlists_are_recognised_by_their_header() {
+        assert!(looks_like_playlist(b"#EXTM3U\n#EXTINF:6,\nseg.ts\n"));
+        assert!(looks_like_playlist(b"\xEF\xBB\xBF#EXTM3U\n"));
+        assert!(looks_like_playlist(b"\r\n#EXTM3U\n"));
+        assert!(!looks_like_playlist(b"\x47\x40\x00\x10"));
+        assert!(!looks_like_playlist(b"<MPD/>"));
+    }
+
+    #[test]
+    fn start_goes_after_the_header_past_a_bom() {
+        for playlist in ["\u{feff}#EXTM3U\n#EXTINF:6,\nseg.ts", "\n  #EXTM3U\n#EXTINF:6,\nseg.ts", "#EXTM3U\r\n#EXTINF:6,\nseg.ts"] {
+            let lines: Vec<String> = set_start(playlist, 4.0).lines().map(|l| l.trim_end().to_string()).collect();
+            assert_eq!(lines, ["#EXTM3U", "#EXT-X-START:TIME-OFFSET=4", "#EXTINF:6,", "seg.ts"], "{playlist:?}");
+        }
+    }
+
+    #[test]
+    fn only_segments_on_cdn_shards_are_pinned() {
+        let base = Url::parse("https://cdn2.example.com/hls/index.m3u8").unwrap();
+        let links = Links::default().with_pinned_host(Some("cdn7.example.com"));
+        let playlist = concat!(
+            "#EXTM3U\n",
+            "#EXT-X-KEY:METHOD=AES-128,URI=\"https://keys.example.com/k1\"\n",
+            "#EXT-X-MAP:URI=\"init.mp4\"\n",
+            "#EXTINF:4,\n",
+            "seg-1.ts\n",
+            "#EXTINF:4,\n",
+            "https://ads.example.net/ad-1.ts\n",
+        );
+        let out = rewrite_playlist(playlist, &base, &links, None).unwrap();
+        let targets: Vec<_> = out.lines().filter_map(|line| {
+            let start = line.find("/fetch?b64=")?;
+            let end = line[start..].find('"').map_or(line.len(), |end| start + end);
+            Some(link_target(&line[start..end]))
+        }).collect();
+        assert_eq!(targets, [
+            "https://keys.example.com/k1",
+            "https://cdn2.example.com/hls/init.mp4",
+            "https://cdn7.example.com/hls/seg-1.ts",
+            "https://ads.example.net/ad-1.ts",
+        ]);
+    }
+}
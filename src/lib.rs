@@ -0,0 +1,3968 @@
+use axum::{
+    extract::{Query, State, rejection::QueryRejection},
+    http::{Method, StatusCode, header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+    body::{Body, Bytes},
+};
+use futures_util::StreamExt;
+use serde::Deserialize;
+use reqwest::{Client, header as reqwest_header};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tower_http::compression::CompressionLayer;
+use tower_http::cors::{AllowOrigin, CorsLayer};
+use tracing::{debug, error, info, warn};
+
+mod access_log;
+pub mod allowlist;
+mod cache;
+mod coalesce;
+pub mod client_ip;
+pub mod config;
+mod dash;
+mod error;
+mod hls;
+mod links;
+mod metrics;
+mod rate_limit;
+mod ssrf;
+pub mod transform;
+mod vtt;
+
+// for services handing out signed /fetch links of their own
+pub use links::sign_url;
+
+// Everything the handlers share, built once from the Config.
+#[derive(Clone)]
+pub struct AppState {
+    client: Arc<Client>,
+    no_redirect_client: Arc<Client>,
+    config: Arc<config::Config>,
+    metrics: Arc<metrics::Metrics>,
+    cache: Option<Arc<cache::Cache>>,
+    // caps simultaneous /fetch requests, None means unbounded
+    concurrency: Option<Arc<tokio::sync::Semaphore>>,
+    access_log: Option<access_log::AccessLog>,
+    flights: Arc<coalesce::Flights>,
+    // background segment fetches started by /prefetch and still running
+    prefetches: Arc<tokio::sync::Semaphore>,
+    // upstream bodies being streamed to clients right now
+    streams: Arc<AtomicUsize>,
+}
+
+impl AppState {
+    pub fn new(config: config::Config) -> Self {
+        let client = build_client(&config, true);
+        let no_redirect_client = build_client(&config, false);
+
+        let cache = (config.cache_bytes > 0).then(|| Arc::new(cache::Cache::new(config.cache_bytes, config.stale_if_error)));
+        let concurrency = config
+            .max_concurrency
+            .map(|permits| Arc::new(tokio::sync::Semaphore::new(permits)));
+        // checked to be writable at startup
+        let access_log = config
+            .access_log
+            .as_deref()
+            .map(|path| access_log::AccessLog::open(path).expect("PROXY_ACCESS_LOG is not writable"));
+
+        AppState {
+            client: Arc::new(client),
+            no_redirect_client: Arc::new(no_redirect_client),
+            config: Arc::new(config),
+            metrics: Arc::new(metrics::Metrics::default()),
+            cache,
+            concurrency,
+            access_log,
+            flights: Arc::default(),
+            prefetches: Arc::new(tokio::sync::Semaphore::new(PREFETCH_TASKS)),
+            streams: Arc::default(),
+        }
+    }
+
+    // Writes out whatever is still buffered, the access log in particular.
+    // Called once the servers have shut down.
+    pub async fn flush(&self) {
+        if let Some(log) = &self.access_log {
+            log.flush().await;
+        }
+    }
+
+    // Streamed responses still open, the ones a shutdown would cut off.
+    pub fn open_streams(&self) -> usize {
+        self.streams.load(Ordering::Relaxed)
+    }
+}
+
+// Upstream HTTP client, shared so connections and TLS sessions are pooled
+// across requests. AppState keeps a second one that leaves redirects to the
+// caller, for `follow_redirects=0`.
+fn build_client(config: &config::Config, follow_redirects: bool) -> Client {
+    // gzip/deflate/brotli bodies are decoded transparently and reqwest drops the
+    // Content-Encoding header, so clients always get identity bodies from us.
+    let mut builder = Client::builder()
+        .timeout(config.timeout)
+        .connect_timeout(config.connect_timeout);
+    if config.block_private_addresses {
+        builder = builder.dns_resolver(Arc::new(ssrf::GuardedResolver));
+    }
+    builder = builder.redirect(match follow_redirects {
+        false => reqwest::redirect::Policy::none(),
+        true => ssrf::redirect_policy(config.max_redirects, config.allowed_hosts.clone(), config.block_private_addresses),
+    });
+    // by default HTTPS upstreams get HTTP/2 when ALPN offers it and
+    // everything else HTTP/1.1. Prior knowledge skips that negotiation and
+    // multiplexes segment fetches over one connection per host, but any
+    // upstream that only speaks HTTP/1.1, including plain-http ones, fails.
+    if config.http2_only {
+        builder = builder.http2_prior_knowledge();
+    }
+    // more idle connections keep bursts of segment fetches off the TCP/TLS
+    // handshake, at the cost of sockets and memory held per host
+    if let Some(idle) = config.pool_idle {
+        builder = builder.pool_max_idle_per_host(idle);
+    }
+    if let Some(upstream) = &config.upstream_proxy {
+        // validated at startup, basic-auth credentials in the URL are picked up by reqwest
+        builder = builder.proxy(reqwest::Proxy::all(upstream.as_str()).unwrap());
+    }
+    builder.build().unwrap()
+}
+
+#[derive(Deserialize)]
+struct FetchQuery {
+    url: Option<String>,
+    // base64url-encoded upstream URL, preferred over `url` when both are set
+    b64: Option<String>,
+    ref_: Option<String>,
+    // `ref` is accepted too; `ref_`, which our rewritten links use, wins when both are set
+    #[serde(rename = "ref")]
+    ref_alias: Option<String>,
+    // HMAC of the upstream URL, required when PROXY_SIGNING_KEY is set
+    sig: Option<String>,
+    // base64url DASH URL template `sig` covers instead, for segment links the
+    // player filled in
+    tpl: Option<String>,
+    // mirror base URL to retry the same path against on 404/410
+    fallback: Option<String>,
+    // per-request upstream timeout in seconds, overriding PROXY_TIMEOUT_SECS
+    timeout: Option<String>,
+    // upstream User-Agent, overriding PROXY_USER_AGENT
+    ua: Option<String>,
+    // playlist nesting depth, set on the links our rewriters emit
+    depth: Option<u32>,
+    // bearer token sent upstream as Authorization, for players that can't set headers
+    auth: Option<String>,
+    // CDN shard host the segment and variant links of a rewritten playlist are pinned to
+    pin_host: Option<String>,
+    // `1` returns a rewritten playlist as plain text, for checking the rewriter
+    rewrite_only: Option<String>,
+    // `0` hands upstream 3xx responses to the client, Location pointing back at /fetch
+    follow_redirects: Option<String>,
+    // seconds into a media playlist the player should start at, as EXT-X-START
+    start: Option<String>,
+    // `1` leaves out the Referer / Origin sent upstream, `0` puts them back
+    // when PROXY_NO_REFERER / PROXY_NO_ORIGIN is set
+    no_referer: Option<String>,
+    no_origin: Option<String>,
+    // Content-Type sent to the client instead of the one the proxy works out
+    ct: Option<String>,
+}
+
+// Builds the proxy's routes and middleware around `state`, CORS included,
+// without binding a socket, so tests and other services can drive it
+// directly. Rewritten playlists link to `/fetch`, so a service nesting this
+// under a prefix has to strip that prefix before requests reach it.
+pub fn build_app(state: AppState) -> Router {
+    let allow_origin = match &state.config.cors_origins {
+        Some(origins) => AllowOrigin::list(origins.clone()),
+        None => AllowOrigin::any(),
+    };
+    let cors_layer = CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods(tower_http::cors::Any)
+        .allow_headers(tower_http::cors::Any);
+
+    let limiter = state.config.rate_limit.map(|rate| {
+        let burst = state.config.rate_burst.unwrap_or(rate.ceil());
+        Arc::new(rate_limit::RateLimiter::new(rate, burst))
+    });
+    let mut fetch_route = get(fetch_handler).head(fetch_handler);
+    if let Some(limiter) = &limiter {
+        fetch_route = fetch_route.layer(rate_limit::RateLimitLayer::new(limiter.clone(), state.config.trust_forwarded));
+    }
+
+    let mut app = Router::new()
+        .route("/health", get(health_check))
+        .route("/ready", get(ready_handler))
+        .route("/version", get(version_handler))
+        .route("/metrics", get(metrics_handler))
+        .route("/fetch", fetch_route);
+    if state.config.info_endpoint {
+        app = app.route("/info", get(info_handler));
+    }
+    // prefetched segments only help if there's a cache to put them in
+    // and they share the /fetch buckets, each call fans out into several fetches
+    if state.cache.is_some() {
+        let mut prefetch_route = get(prefetch_handler);
+        if let Some(limiter) = limiter {
+            prefetch_route = prefetch_route.layer(rate_limit::RateLimitLayer::new(limiter, state.config.trust_forwarded));
+        }
+        app = app.route("/prefetch", prefetch_route);
+    }
+    app = app
+        .layer(axum::middleware::from_fn(error::negotiate))
+        .layer(CompressionLayer::new().compress_when(is_compressible))
+        .layer(cors_layer);
+    // outermost, so it sees what actually went out: rate limiting, CORS and
+    // compression included
+    if let Some(log) = &state.access_log {
+        app = app.layer(axum::middleware::from_fn_with_state(
+            (log.clone(), state.config.trust_forwarded),
+            access_log::log_requests,
+        ));
+    }
+    app.with_state(state)
+}
+
+// Playlists and subtitles are plain text and shrink a lot; segments and
+// images are already compressed, so gzipping them only costs CPU.
+fn is_compressible(
+    _: StatusCode,
+    _: axum::http::Version,
+    headers: &HeaderMap,
+    _: &axum::http::Extensions,
+) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|ct| {
+            ["application/vnd.apple.mpegurl", "text/vtt", "application/dash+xml"]
+                .iter()
+                .any(|text| ct.starts_with(text))
+        })
+}
+
+async fn health_check() -> &'static str {
+    "Hello via Axum!"
+}
+
+// Which build is running; commit and build time (unix seconds) come from build.rs.
+async fn version_handler() -> impl IntoResponse {
+    axum::Json(serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "commit": env!("PROXY_GIT_COMMIT"),
+        "built": env!("PROXY_BUILD_TIMESTAMP").parse::<u64>().unwrap_or(0),
+    }))
+}
+
+const READY_TIMEOUT: Duration = Duration::from_secs(3);
+
+// Readiness probe: unlike /health this checks that PROXY_HEALTH_URL answers
+// through the same client and upstream proxy /fetch uses. Any response short
+// of a 5xx counts, the body reports how long it took.
+async fn ready_handler(State(state): State<AppState>) -> Response {
+    let Some(url) = &state.config.health_url else {
+        return "ready".into_response();
+    };
+
+    let started = Instant::now();
+    let result = state.client.head(url.clone()).timeout(READY_TIMEOUT).send().await;
+    let latency_ms = started.elapsed().as_millis();
+
+    match result {
+        Ok(res) if !res.status().is_server_error() => format!("ready, {latency_ms} ms").into_response(),
+        Ok(res) => {
+            warn!(status = res.status().as_u16(), "readiness check got an upstream error");
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("not ready: {url} returned {}, after {latency_ms} ms", res.status())
+            ).into_response()
+        }
+        Err(e) => {
+            warn!("readiness check failed: {e}");
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                format!("not ready: {e}, after {latency_ms} ms")
+            ).into_response()
+        }
+    }
+}
+
+async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(),
+    )
+}
+
+// Debug view of an upstream's response: status and headers as JSON, the body
+// is never read. Only routed when PROXY_ENABLE_INFO is set.
+async fn info_handler(
+    State(state): State<AppState>,
+    params: Result<Query<FetchQuery>, QueryRejection>,
+    client_headers: HeaderMap,
+) -> Response {
+    let params = match fetch_query(params) {
+        Ok(params) => params,
+        Err(e) => return e.into_response(),
+    };
+    let parsed = match resolve_target(&state, &params).await {
+        Ok(parsed) => parsed,
+        Err(e) => return e.into_response(),
+    };
+    let user_agent = match user_agent(&state, &params) {
+        Ok(user_agent) => user_agent,
+        Err(e) => return e.into_response(),
+    };
+    let mut headers = build_upstream_headers(&parsed, params.ref_.as_deref(), user_agent, &client_headers, omitted_headers(&state, &params));
+    match bearer_authorization(&params) {
+        Ok(Some(authorization)) => {
+            headers.insert(reqwest_header::AUTHORIZATION, authorization);
+        }
+        Ok(None) => {}
+        Err(e) => return e.into_response(),
+    }
+
+    let res = match send_with_retries(&state, true, Method::GET, &parsed, &headers, None).await {
+        Ok(res) => res,
+        Err(e) if ssrf::is_blocked_error(&e) => return error::ProxyError::new(
+            StatusCode::FORBIDDEN,
+            "FORBIDDEN_TARGET",
+            "Forbidden target: redirected to a non-public address"
+        ).into_response(),
+        Err(e) if ssrf::is_host_not_allowed_error(&e) => return redirect_not_allowed().into_response(),
+        Err(e) => return upstream_error(&e).into_response(),
+    };
+
+    // repeated headers such as Set-Cookie keep every value
+    let mut response_headers = std::collections::BTreeMap::<String, Vec<String>>::new();
+    for (name, value) in res.headers() {
+        response_headers
+            .entry(name.to_string())
+            .or_default()
+            .push(String::from_utf8_lossy(value.as_bytes()).into_owned());
+    }
+
+    axum::Json(serde_json::json!({
+        "url": res.url().as_str(),
+        "status": res.status().as_u16(),
+        "headers": response_headers,
+    }))
+    .into_response()
+}
+
+// Prefetch tasks in flight at once across all clients, and the most segments
+// one /prefetch asks for.
+const PREFETCH_TASKS: usize = 16;
+const PREFETCH_MAX_SEGMENTS: usize = 10;
+
+#[derive(Deserialize)]
+struct PrefetchQuery {
+    // how many of the playlist's last segments to warm, 3 by default
+    n: Option<usize>,
+}
+
+// Warms the cache with the last `n` segments of a media playlist. The
+// playlist goes through /fetch as usual and its rewritten segment links are
+// fetched in the background, each taking a /fetch concurrency permit like
+// any other request; answers 202 with how many were queued. Requests with
+// credentials are refused, as nothing they fetch would be cached.
+async fn prefetch_handler(
+    State(state): State<AppState>,
+    params: Result<Query<FetchQuery>, QueryRejection>,
+    count: Result<Query<PrefetchQuery>, QueryRejection>,
+    client_headers: HeaderMap,
+) -> Response {
+    let count = match count {
+        Ok(Query(query)) => query.n.unwrap_or(3).min(PREFETCH_MAX_SEGMENTS),
+        Err(e) => return error::ProxyError::new(
+            StatusCode::BAD_REQUEST,
+            "INVALID_QUERY",
+            format!("Invalid query string: {}", e.body_text())
+        ).into_response(),
+    };
+    // responses to credentialed fetches are never cached, so there'd be
+    // nothing to warm; token-gated streams are left to the player
+    let credentialed = client_headers.contains_key(header::COOKIE)
+        || client_headers.contains_key(header::AUTHORIZATION)
+        || params.as_ref().is_ok_and(|params| params.auth.is_some());
+    if credentialed {
+        return error::ProxyError::new(
+            StatusCode::BAD_REQUEST,
+            "CREDENTIALED_PREFETCH",
+            "Prefetch can't warm the cache for requests with credentials"
+        ).into_response();
+    }
+    // links don't carry `ua`, the segments should still be fetched with it
+    let ua = params.as_ref().ok().and_then(|params| params.ua.clone());
+
+    let res = fetch(state.clone(), params, Method::GET, client_headers, false, &mut None).await;
+    if res.status() != StatusCode::OK {
+        return res;
+    }
+    let is_playlist = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|ct| ct.starts_with("application/vnd.apple.mpegurl"));
+    let text = match axum::body::to_bytes(res.into_body(), state.config.max_text_bytes as usize).await {
+        Ok(body) if is_playlist => String::from_utf8_lossy(&body).into_owned(),
+        _ => String::new(),
+    };
+    if hls::playlist_kind(&text) != Some(hls::PlaylistKind::Media) {
+        return error::ProxyError::new(
+            StatusCode::BAD_REQUEST,
+            "NOT_A_MEDIA_PLAYLIST",
+            "Prefetch needs an HLS media playlist"
+        ).into_response();
+    }
+
+    let links: Vec<&str> = text.lines().filter(|line| line.starts_with("/fetch?")).collect();
+    let mut queued = 0;
+    for link in &links[links.len().saturating_sub(count)..] {
+        let Ok(permit) = state.prefetches.clone().try_acquire_owned() else {
+            debug!("prefetch task limit reached");
+            break;
+        };
+        let Ok(uri) = link.parse::<axum::http::Uri>() else {
+            continue;
+        };
+        let query = Query::<FetchQuery>::try_from_uri(&uri).map(|mut query| {
+            query.ua = query.ua.take().or_else(|| ua.clone());
+            query
+        });
+        let state = state.clone();
+        tokio::spawn(async move {
+            let res = fetch(state, query, Method::GET, HeaderMap::new(), true, &mut None).await;
+            let status = res.status();
+            // the cache stores the body once it has been read through
+            let read = axum::body::to_bytes(res.into_body(), usize::MAX).await;
+            debug!(status = status.as_u16(), ok = read.is_ok(), "prefetched segment");
+            drop(permit);
+        });
+        queued += 1;
+    }
+
+    (StatusCode::ACCEPTED, axum::Json(serde_json::json!({ "queued": queued }))).into_response()
+}
+
+const X_PROXY_DEPTH: header::HeaderName = header::HeaderName::from_static("x-proxy-depth");
+
+// Client request headers copied onto the upstream request when present.
+const FORWARDED_CLIENT_HEADERS: [header::HeaderName; 6] = [
+    header::IF_NONE_MATCH,
+    header::IF_MODIFIED_SINCE,
+    header::IF_RANGE,
+    header::ACCEPT_LANGUAGE,
+    // token-gated origins check the cookies they set on the manifest
+    header::COOKIE,
+    header::AUTHORIZATION,
+];
+
+#[tracing::instrument(
+    name = "fetch",
+    skip_all,
+    fields(url = tracing::field::Empty, client = tracing::field::Empty)
+)]
+async fn fetch_handler(
+    State(state): State<AppState>,
+    params: Result<Query<FetchQuery>, QueryRejection>,
+    method: Method,
+    extensions: axum::http::Extensions,
+    client_headers: HeaderMap,
+) -> Response {
+    if let Some(ip) = client_ip::client_ip(&client_headers, &extensions, state.config.trust_forwarded) {
+        tracing::Span::current().record("client", tracing::field::display(ip));
+    }
+    let mut cache_outcome = None;
+    let mut res = fetch(state.clone(), params, method, client_headers, false, &mut cache_outcome).await;
+    if let Some(outcome) = cache_outcome {
+        let headers = res.headers_mut();
+        headers.insert(X_PROXY_CACHE, HeaderValue::from_static(outcome.as_str()));
+        if let CacheOutcome::Hit(age) | CacheOutcome::Stale(age) = outcome {
+            headers.insert(header::AGE, HeaderValue::from(age.as_secs()));
+        }
+        if let CacheOutcome::Stale(_) = outcome {
+            headers.insert(header::WARNING, HeaderValue::from_static("111 - \"Revalidation Failed\""));
+        }
+    }
+    // PROXY_STRIP_HEADERS, applied last so every response path is covered
+    for name in &state.config.strip_headers {
+        res.headers_mut().remove(name);
+    }
+    res
+}
+
+async fn fetch(
+    state: AppState,
+    params: Result<Query<FetchQuery>, QueryRejection>,
+    method: Method,
+    client_headers: HeaderMap,
+    // a /prefetch segment fetch, worth buffering for the cache whatever its length
+    prefetch: bool,
+    cache_outcome: &mut Option<CacheOutcome>,
+) -> Response {
+    // held until the response is done, which for streamed bodies means until
+    // the stream finishes or the client goes away
+    let permit = match &state.concurrency {
+        Some(semaphore) => match semaphore.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                return (
+                    [(header::RETRY_AFTER, "1")],
+                    error::ProxyError::new(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        "TOO_MANY_REQUESTS",
+                        "Too many concurrent requests"
+                    ),
+                ).into_response();
+            }
+        },
+        None => None,
+    };
+
+    let params = match fetch_query(params) {
+        Ok(params) => params,
+        Err(e) => return e.into_response(),
+    };
+    // playlists nesting through /fetch, whether via our own links or a proxy
+    // chain looping back to us, are cut off at PROXY_MAX_DEPTH
+    let depth = client_headers
+        .get(X_PROXY_DEPTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u32>().ok())
+        .unwrap_or(0)
+        .max(params.depth.unwrap_or(0));
+    if depth > state.config.max_depth {
+        warn!(depth, "playlist nesting too deep, refusing");
+        return error::ProxyError::new(
+            StatusCode::LOOP_DETECTED,
+            "LOOP_DETECTED",
+            "Playlist nesting too deep"
+        ).into_response();
+    }
+
+    let parsed = match resolve_target(&state, &params).await {
+        Ok(parsed) => parsed,
+        Err(e) => return e.into_response(),
+    };
+
+    // opt-in mirror tried once when the primary upstream 404s or 410s. `sig`
+    // only covers the primary URL, so with signing on a fallback would let
+    // anyone holding a signed link fetch a host of their choosing
+    if state.config.signing_key.is_some() && params.fallback.is_some() {
+        return error::ProxyError::new(
+            StatusCode::FORBIDDEN,
+            "UNSIGNED_PARAMETER",
+            "fallback is not allowed when links are signed"
+        ).into_response();
+    }
+    // same for pin_host: emitted links would be signed over whatever host it names
+    if state.config.signing_key.is_some() && params.pin_host.is_some() {
+        return error::ProxyError::new(
+            StatusCode::FORBIDDEN,
+            "UNSIGNED_PARAMETER",
+            "pin_host is not allowed when links are signed"
+        ).into_response();
+    }
+    let fallback = match params.fallback.as_deref().map(url::Url::parse) {
+        Some(Ok(base)) => {
+            let alternate = fallback_url(&base, &parsed);
+            if let Err(e) = check_target(&state, &alternate).await {
+                return e.into_response();
+            }
+            Some(alternate)
+        }
+        Some(Err(_)) => return error::ProxyError::new(
+            StatusCode::BAD_REQUEST,
+            "INVALID_FALLBACK",
+            "Invalid fallback URL"
+        ).into_response(),
+        None => None,
+    };
+    if let Some(host) = &params.pin_host
+        && (url::Host::parse(host).is_err() || host.contains(':') && !host.starts_with('['))
+    {
+        return error::ProxyError::new(
+            StatusCode::BAD_REQUEST,
+            "INVALID_PIN_HOST",
+            "Invalid pin_host parameter, expected a bare host name"
+        ).into_response();
+    }
+
+    let timeout = match params.timeout.as_deref().map(parse_timeout) {
+        Some(Some(timeout)) => Some(timeout),
+        Some(None) => return error::ProxyError::new(
+            StatusCode::BAD_REQUEST,
+            "INVALID_TIMEOUT",
+            "Invalid timeout, expected a positive number of seconds"
+        ).into_response(),
+        None => None,
+    };
+    let start = match params.start.as_deref().map(parse_start) {
+        Some(Some(start)) => Some(start),
+        Some(None) => return error::ProxyError::new(
+            StatusCode::BAD_REQUEST,
+            "INVALID_START",
+            "Invalid start, expected a non-negative number of seconds"
+        ).into_response(),
+        None => None,
+    };
+    let forced_content_type = match params.ct.as_deref().map(forced_content_type) {
+        Some(Ok(ct)) => Some(ct),
+        Some(Err(e)) => return e.into_response(),
+        None => None,
+    };
+
+    let user_agent = match user_agent(&state, &params) {
+        Ok(user_agent) => user_agent,
+        Err(e) => return e.into_response(),
+    };
+    let mut headers = build_upstream_headers(&parsed, params.ref_.as_deref(), user_agent, &client_headers, omitted_headers(&state, &params));
+    match bearer_authorization(&params) {
+        Ok(Some(authorization)) => {
+            headers.insert(reqwest_header::AUTHORIZATION, authorization);
+        }
+        Ok(None) => {}
+        Err(e) => return e.into_response(),
+    }
+    // lets another instance, or this one reached through its own URL, see the nesting
+    headers.insert(X_PROXY_DEPTH, HeaderValue::from(depth + 1));
+
+    // HEAD probes go straight to the origin. The cache only holds whole
+    // objects, so ranged responses are never stored, but a player's Range
+    // can be cut out of a cached full segment.
+    // a cached body would hide the redirect the client asked to see
+    let follow_redirects = params.follow_redirects.as_deref() != Some("0");
+    let is_head = method == Method::HEAD;
+    let client_range = client_headers.get(header::RANGE).and_then(|v| v.to_str().ok());
+    // what the origin answers with credentials or cookies is that client's alone
+    let credentialed = headers.contains_key(reqwest_header::AUTHORIZATION) || headers.contains_key(reqwest_header::COOKIE);
+    let cache = state.cache.as_deref().filter(|_| !is_head && follow_redirects && !credentialed);
+    let cache_key = cache::normalize_url(&parsed, state.config.sort_query);
+
+    let fresh = cache.and_then(|c| c.get_fresh(&cache_key));
+    let cached = fresh.as_ref().and_then(|entry| match client_range {
+        // a slice of a playlist can't be rewritten, those get the whole body
+        None => Some((entry.url.clone(), entry.status, entry.headers.clone(), entry.body.clone())),
+        Some(_) if is_rewritten(&entry.headers, &entry.url, &entry.body) => {
+            Some((entry.url.clone(), entry.status, entry.headers.clone(), entry.body.clone()))
+        }
+        Some(range) if entry.is_complete() => {
+            let len = entry.body.len();
+            let range = cache::resolve_range(range, len)?;
+            let mut headers = entry.headers.clone();
+            headers.insert(header::CONTENT_LENGTH, HeaderValue::from(range.len()));
+            headers.insert(
+                header::CONTENT_RANGE,
+                HeaderValue::from_str(&format!("bytes {}-{}/{len}", range.start, range.end - 1)).ok()?,
+            );
+            Some((entry.url.clone(), StatusCode::PARTIAL_CONTENT, headers, entry.body.slice(range)))
+        }
+        Some(_) => None,
+    });
+    *cache_outcome = Some(match (&cached, &fresh) {
+        (Some(_), Some(entry)) => CacheOutcome::Hit(entry.age()),
+        _ if cache.is_some() && client_range.is_none() => CacheOutcome::Miss,
+        _ => CacheOutcome::Bypass,
+    });
+
+    // identical GETs in flight at the same time share one upstream fetch;
+    // ranged requests are left alone, their bodies differ
+    let started = Instant::now();
+    let mut leader = None;
+    let shared = match cached {
+        None if state.config.coalesce && method == Method::GET && client_range.is_none() && follow_redirects => {
+            match state.flights.join(coalesce::key(&cache_key, &headers)) {
+                coalesce::Role::Leader(flight) => {
+                    leader = Some(flight);
+                    None
+                }
+                coalesce::Role::Follower(flight) => coalesce::follow(flight).await,
+            }
+        }
+        _ => None,
+    };
+
+    // how long the origin took to answer, retries and fallback included
+    let mut upstream_latency = None;
+    // `source_url` is where the body actually came from, after redirects and
+    // fallback; relative playlist entries resolve against it
+    let (source_url, status, headers_copy, upstream) = match (cached, shared) {
+        (Some((url, status, headers, body)), _) => {
+            debug!("serving from cache");
+            (url, status, headers, UpstreamBody::Cached(body))
+        }
+        (None, Some(shared)) => {
+            debug!("coalesced with an in-flight fetch");
+            upstream_latency = Some(started.elapsed());
+            (shared.url.clone(), shared.status, shared.headers.clone(), UpstreamBody::Cached(shared.body.clone()))
+        }
+        (None, None) => {
+            let mut result = send_with_retries(&state, follow_redirects, method.clone(), &parsed, &headers, timeout).await;
+
+            if let (Ok(res), Some(alternate)) = (&result, &fallback)
+                && matches!(res.status(), StatusCode::NOT_FOUND | StatusCode::GONE)
+            {
+                warn!(status = res.status().as_u16(), fallback = %alternate, "primary upstream missing, trying fallback");
+                // if the mirror fails too the client gets the original error
+                if let Ok(alternate_res) = send_with_retries(&state, follow_redirects, method, alternate, &headers, timeout).await
+                    && alternate_res.status().is_success()
+                {
+                    result = Ok(alternate_res);
+                }
+            }
+
+            // stale-if-error: while the origin flaps an expired copy beats an error
+            let failed = match &result {
+                Ok(res) => res.status().is_server_error(),
+                Err(e) => !ssrf::is_blocked_error(e),
+            };
+            let stale = cache
+                .filter(|_| failed && client_range.is_none())
+                .and_then(|c| c.get_stale(&cache_key));
+
+            match (result, stale) {
+                (result, Some(entry)) => {
+                    match &result {
+                        Ok(res) => state.metrics.record_response(res.status().as_u16(), started.elapsed()),
+                        Err(_) => state.metrics.record_error(started.elapsed()),
+                    }
+                    warn!(age_secs = entry.age().as_secs(), "upstream failed, serving a stale cached response");
+                    *cache_outcome = Some(CacheOutcome::Stale(entry.age()));
+                    (entry.url.clone(), entry.status, entry.headers.clone(), UpstreamBody::Cached(entry.body.clone()))
+                }
+                (Ok(res), None) => {
+                    let latency = started.elapsed();
+                    upstream_latency = Some(latency);
+                    state.metrics.record_response(res.status().as_u16(), latency);
+                    if let Err(e) = check_upstream_headers(&state.config, &res) {
+                        return e.into_response();
+                    }
+                    info!(
+                        status = res.status().as_u16(),
+                        elapsed_ms = latency.as_millis() as u64,
+                        "upstream responded"
+                    );
+                    let (url, status, headers) = (res.url().clone(), res.status(), res.headers().clone());
+                    // whoever waited needs the whole body, so the leader buffers it
+                    let leader = leader.take();
+                    let followers = leader.as_ref().is_some_and(coalesce::Leader::has_followers);
+                    match leader.zip(share_limit(&state.config, &res, followers)) {
+                        Some((leader, limit)) => match read_body(res, limit).await {
+                            Ok(body) => {
+                                leader.share(coalesce::Shared {
+                                    url: url.clone(),
+                                    status,
+                                    headers: headers.clone(),
+                                    body: body.clone(),
+                                });
+                                (url, status, headers, UpstreamBody::Buffered(body))
+                            }
+                            Err(e) => return e.into_response(),
+                        },
+                        None => (url, status, headers, UpstreamBody::Live(res)),
+                    }
+                }
+                (Err(e), None) if ssrf::is_blocked_error(&e) => {
+                    state.metrics.record_error(started.elapsed());
+                    return error::ProxyError::new(
+                        StatusCode::FORBIDDEN,
+                        "FORBIDDEN_TARGET",
+                        "Forbidden target: redirected to a non-public address"
+                    ).into_response();
+                }
+                (Err(e), None) if ssrf::is_host_not_allowed_error(&e) => {
+                    state.metrics.record_error(started.elapsed());
+                    return redirect_not_allowed().into_response();
+                }
+                (Err(e), None) => {
+                    state.metrics.record_error(started.elapsed());
+                    error!(elapsed_ms = started.elapsed().as_millis() as u64, "proxy error: {e:?}");
+                    return upstream_error(&e).into_response();
+                }
+            }
+        }
+    };
+
+    // shows up in the browser's network panel next to our own timings
+    let server_timing = match (&cache_outcome, upstream_latency) {
+        // the failed origin attempt isn't what the client got
+        (Some(CacheOutcome::Stale(_)), _) => "cache;desc=\"stale\"".to_string(),
+        (_, Some(latency)) => format!("upstream;dur={:.1}", latency.as_secs_f64() * 1000.0),
+        (_, None) => "cache;desc=\"hit\"".to_string(),
+    };
+
+    // helpful debug
+    if status == StatusCode::GONE {
+        warn!(headers = ?headers_copy, "upstream returned 410 Gone");
+    }
+
+    // the client's cached copy is still good, pass the validators back as-is
+    if status == StatusCode::NOT_MODIFIED {
+        let mut builder = Response::builder()
+            .status(status)
+            .header("server-timing", &server_timing)
+            .header(X_PROXY_FINAL_URL, source_url.as_str());
+        for name in [header::ETAG, header::LAST_MODIFIED, header::CACHE_CONTROL] {
+            if let Some(value) = headers_copy.get(&name) {
+                builder = builder.header(name, value.clone());
+            }
+        }
+        return builder.body(Body::empty()).unwrap_or_else(|_| {
+            error::ProxyError::body_assembly().into_response()
+        });
+    }
+
+    // follow_redirects=0: the redirect goes to the client, its target linked
+    // back through /fetch so following it keeps going through the proxy
+    if status.is_redirection()
+        && let Some(location) = headers_copy.get(header::LOCATION).and_then(|v| v.to_str().ok())
+        && let Ok(target) = source_url.join(location)
+    {
+        let links = links::Links::new(state.config.signing_key.as_deref())
+            .with_referer(params.ref_.as_deref())
+            .with_auth(params.auth.as_deref())
+            .with_pinned_host(params.pin_host.as_deref())
+            .with_omitted(query_flag(&params.no_referer), query_flag(&params.no_origin))
+            .with_depth(depth + 1);
+        let builder = Response::builder()
+            .status(status)
+            .header(header::LOCATION, format!("{}&follow_redirects=0", links.link(&target)))
+            .header("cache-control", ERROR_CACHE_CONTROL)
+            .header("server-timing", &server_timing)
+            .header(X_PROXY_FINAL_URL, source_url.as_str());
+        return append_set_cookies(builder, &headers_copy).body(Body::empty()).unwrap_or_else(|_| {
+            error::ProxyError::body_assembly().into_response()
+        });
+    }
+
+    let content_type = headers_copy
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("text/plain")
+        .to_string();
+
+    // PROXY_ALLOWED_CONTENT_TYPES keeps the proxy from serving arbitrary files,
+    // e.g. HTML for phishing. Responses without a Content-Type don't match.
+    if let Some(allowed) = &state.config.allowed_content_types {
+        let upstream_type = headers_copy
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.trim().to_ascii_lowercase());
+        if !upstream_type.as_deref().is_some_and(|ct| allowed.iter().any(|prefix| ct.starts_with(prefix.as_str()))) {
+            warn!(content_type = upstream_type.as_deref().unwrap_or(""), "upstream content type not allowed");
+            return error::ProxyError::new(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "CONTENT_TYPE_NOT_ALLOWED",
+                "Upstream content type is not allowed"
+            ).into_response();
+        }
+    }
+
+    let original_cache_control = headers_copy
+        .get(header::CACHE_CONTROL)
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.to_string());
+
+    let original_cdn_cache_control = headers_copy
+        .get("CDN-Cache-Control")
+        .and_then(|v| v.to_str().ok())
+        .map(|s| s.to_string());
+
+    // some origins label playlists application/octet-stream or text/plain,
+    // on URLs without an .m3u8 extension; the body gives them away. Only
+    // bodies small enough to be playlists are buffered to look, and those of
+    // unknown length only as far as their first bytes
+    let sniffable = !is_head
+        && status == StatusCode::OK
+        && is_generic_content_type(&content_type)
+        && ![".m3u8", ".mpd", ".vtt"].iter().chain(SEGMENT_EXTENSIONS).any(|ext| parsed.path().ends_with(ext));
+    let upstream = match upstream {
+        UpstreamBody::Live(res) if sniffable && res.content_length().is_some_and(|len| len <= state.config.max_text_bytes) => {
+            match read_body(res, Some(state.config.max_text_bytes)).await {
+                Ok(body) => UpstreamBody::Buffered(body),
+                Err(e) => return e.into_response(),
+            }
+        }
+        UpstreamBody::Live(mut res) if sniffable && res.content_length().is_none() => {
+            let (prefix, complete) = match read_prefix(&mut res, SNIFF_BYTES).await {
+                Ok(prefix) => prefix,
+                Err(e) => return e.into_response(),
+            };
+            if complete {
+                UpstreamBody::Buffered(Bytes::from(prefix))
+            } else if hls::looks_like_playlist(&prefix) {
+                match read_rest(prefix, res, Some(state.config.max_text_bytes)).await {
+                    Ok(body) => UpstreamBody::Buffered(body),
+                    Err(e) => return e.into_response(),
+                }
+            } else {
+                UpstreamBody::Peeked(Bytes::from(prefix), res)
+            }
+        }
+        upstream => upstream,
+    };
+    let sniffed_m3u8 = sniffable && match &upstream {
+        UpstreamBody::Cached(body) | UpstreamBody::Buffered(body) => hls::looks_like_playlist(body),
+        UpstreamBody::Live(_) | UpstreamBody::Peeked(..) => false,
+    };
+    if sniffed_m3u8 {
+        debug!(content_type, "playlist sniffed from the body");
+    }
+
+    let is_m3u8 = sniffed_m3u8 || content_type.contains("application/vnd.apple.mpegurl") || parsed.path().ends_with(".m3u8");
+    let is_mpd = content_type.contains("application/dash+xml") || parsed.path().ends_with(".mpd");
+    let is_vtt = !is_m3u8 && (content_type.contains("text/vtt") || parsed.path().ends_with(".vtt"));
+
+    let (cache_control_header, cdn_cache_control_header, proxied_content_type) =
+        if is_m3u8 || is_mpd {
+            let overridden = state.config.playlist_cache_control.clone();
+            let cache_control = overridden.clone()
+                .or(original_cache_control)
+                .unwrap_or_else(|| "public, max-age=18000, stale-while-revalidate=300".to_string());
+            let cdn_cache = overridden
+                .or(original_cdn_cache_control)
+                .unwrap_or_else(|| "max-age=18000".to_string());
+            let manifest_type = if is_m3u8 {
+                "application/vnd.apple.mpegurl"
+            } else {
+                "application/dash+xml"
+            };
+            (cache_control, cdn_cache, manifest_type.to_string())
+        } else {
+            let overridden = state.config.segment_cache_control.clone();
+            let cache_control = overridden.clone()
+                .or(original_cache_control)
+                .unwrap_or_else(|| "public, max-age=2592000, stale-while-revalidate=86400".to_string());
+            let cdn_cache = overridden
+                .or(original_cdn_cache_control)
+                .unwrap_or_else(|| "max-age=2592000".to_string());
+            let proxied_type = if content_type.contains("video/mp2t") || parsed.path().ends_with(".ts") {
+                "video/mp2t".to_string()
+            } else {
+                content_type.clone()
+            };
+            (cache_control, cdn_cache, proxied_type)
+        };
+
+    let proxied_content_type = match forced_content_type {
+        Some(ct) => ct,
+        None => proxied_content_type,
+    };
+
+    // origin errors are often transient, a 404 cached for a month by a CDN
+    // would outlive the fix
+    let (cache_control_header, cdn_cache_control_header) = if status.as_u16() >= 400 {
+        (ERROR_CACHE_CONTROL.to_string(), ERROR_CACHE_CONTROL.to_string())
+    } else if credentialed {
+        (private_cache_control(&cache_control_header), ERROR_CACHE_CONTROL.to_string())
+    } else {
+        (cache_control_header, cdn_cache_control_header)
+    };
+
+    // freshly fetched whole objects go into the cache, for as long as the
+    // origin allows and otherwise for as long as we tell clients to cache them
+    let default_ttl = Duration::from_secs(if is_m3u8 || is_mpd { 18000 } else { 2592000 });
+    // responses setting cookies are per-client and never shared through the cache
+    let cache_ttl = match (&upstream, cache) {
+        (UpstreamBody::Live(_) | UpstreamBody::Peeked(..) | UpstreamBody::Buffered(_), Some(_))
+            if matches!(status, StatusCode::OK | StatusCode::PARTIAL_CONTENT)
+                && client_range.is_none()
+                && !headers_copy.contains_key(header::SET_COOKIE) =>
+        {
+            cache::ttl_from_headers(&headers_copy, default_ttl)
+        }
+        _ => None,
+    };
+    // fetched live but not storable, e.g. no-store or Set-Cookie
+    if matches!(upstream, UpstreamBody::Live(_) | UpstreamBody::Peeked(..) | UpstreamBody::Buffered(_)) && cache_ttl.is_none() {
+        *cache_outcome = Some(CacheOutcome::Bypass);
+    }
+    let store = |body: &Bytes| {
+        if let (Some(cache), Some(ttl)) = (cache, cache_ttl) {
+            cache.insert(cache_key.clone(), source_url.clone(), status, headers_copy.clone(), body.clone(), ttl);
+        }
+    };
+
+    // no body to rewrite or stream, just report what a GET would return
+    if is_head {
+        let mut builder = Response::builder()
+            .status(status)
+            .header("content-type", proxied_content_type)
+            .header("cache-control", cache_control_header)
+            .header("CDN-Cache-Control", cdn_cache_control_header)
+            .header("server-timing", &server_timing)
+            .header(X_PROXY_FINAL_URL, source_url.as_str());
+        builder = append_set_cookies(builder, &headers_copy);
+        builder = copy_headers(builder, &state.config.copy_headers, &headers_copy);
+        // a rewritten playlist's length differs from the upstream one
+        let passthrough: &[header::HeaderName] = if is_m3u8 || is_mpd {
+            &[header::ACCEPT_RANGES]
+        } else {
+            &[header::CONTENT_LENGTH, header::ACCEPT_RANGES, header::CONTENT_RANGE]
+        };
+        for name in passthrough {
+            if let Some(value) = headers_copy.get(name) {
+                builder = builder.header(name, value.clone());
+            }
+        }
+        return builder.body(Body::empty()).unwrap_or_else(|_| {
+            error::ProxyError::body_assembly().into_response()
+        });
+    }
+
+    // text bodies are always buffered for rewriting, so they get their own,
+    // smaller limit; anything declaring a larger size is refused before reading
+    let is_text = is_m3u8 || is_mpd || is_vtt;
+    let body_limit = if is_text {
+        Some(state.config.max_text_bytes)
+    } else {
+        state.config.max_body_bytes
+    };
+    let declared_length = headers_copy
+        .get(header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if let (Some(limit), Some(len)) = (body_limit, declared_length)
+        && len > limit
+    {
+        warn!(len, limit, "upstream body exceeds size limit");
+        return payload_too_large().into_response();
+    }
+
+    // text formats whose embedded URLs have to be rewritten through /fetch
+    if is_text {
+        let raw = match upstream.bytes(body_limit).await.and_then(|raw| gunzip_unlabelled(raw, body_limit)) {
+            Ok(raw) => raw,
+            Err(e) => return e.into_response(),
+        };
+        let text = decode_text(&raw, &content_type);
+
+        // a live window changes every target duration, so it's only ever
+        // cached briefly and never kept in our cache, unless the operator
+        // chose a playlist Cache-Control
+        let live = is_m3u8 && status.is_success() && hls::is_live(&text);
+        let (cache_control_header, cdn_cache_control_header) =
+            if live && state.config.playlist_cache_control.is_none() && credentialed {
+                (private_cache_control(LIVE_PLAYLIST_CACHE_CONTROL), ERROR_CACHE_CONTROL.to_string())
+            } else if live && state.config.playlist_cache_control.is_none() {
+                (LIVE_PLAYLIST_CACHE_CONTROL.to_string(), LIVE_PLAYLIST_CACHE_CONTROL.to_string())
+            } else {
+                (cache_control_header, cdn_cache_control_header)
+            };
+        // the next poll has to see the origin's latest window
+        if live {
+            *cache_outcome = Some(CacheOutcome::Bypass);
+        } else {
+            store(&raw);
+        }
+
+        let links = links::Links::new(state.config.signing_key.as_deref())
+            .with_referer(params.ref_.as_deref())
+            .with_auth(params.auth.as_deref())
+            .with_pinned_host(params.pin_host.as_deref())
+            .with_omitted(query_flag(&params.no_referer), query_flag(&params.no_origin))
+            .with_depth(depth + 1);
+        let rewritten = if is_m3u8 {
+            let kind = hls::playlist_kind(&text);
+            debug!(?kind, "rewriting playlist");
+            let text = match start {
+                Some(offset) if kind == Some(hls::PlaylistKind::Media) => hls::set_start(&text, offset),
+                _ => text,
+            };
+            let max_lines = state.config.max_playlist_lines;
+            match hls::rewrite_playlist(&text, &source_url, &links, (max_lines > 0).then_some(max_lines)) {
+                Ok(rewritten) => rewritten,
+                Err(hls::TooManyLines) => {
+                    warn!(limit = max_lines, "playlist exceeds line limit");
+                    return error::ProxyError::new(
+                        StatusCode::PAYLOAD_TOO_LARGE,
+                        "PLAYLIST_TOO_LONG",
+                        "Upstream playlist has too many lines"
+                    ).into_response();
+                }
+            }
+        } else if is_mpd {
+            dash::rewrite_manifest(&text, &source_url, &links).unwrap_or_else(|e| {
+                warn!("mpd rewrite failed, passing manifest through: {e:?}");
+                text
+            })
+        } else {
+            vtt::rewrite_vtt(&text, &source_url, &links)
+        };
+
+        // our own validator, the upstream's covers a different body. The raw
+        // playlist is in the cache, so revalidating doesn't go back to the origin
+        let etag = playlist_etag(&rewritten);
+        if status == StatusCode::OK && if_none_match(&client_headers, &etag) {
+            return Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header(header::ETAG, &etag)
+                .header("cache-control", cache_control_header)
+                .header("CDN-Cache-Control", cdn_cache_control_header)
+                .header("server-timing", &server_timing)
+                .header(X_PROXY_FINAL_URL, source_url.as_str())
+                .body(Body::empty())
+                .unwrap_or_else(|_| {
+                    error::ProxyError::body_assembly().into_response()
+                });
+        }
+
+        state.metrics.add_bytes(rewritten.len() as u64);
+
+        // shown in a browser rather than handed to a player, and never cached
+        if params.rewrite_only.as_deref() == Some("1") {
+            return Response::builder()
+                .status(status)
+                .header("content-type", "text/plain; charset=utf-8")
+                .header("cache-control", "no-store")
+                .header("x-proxy-rewrite-only", "rewritten manifest, segments were not fetched")
+                .header("content-length", rewritten.len())
+                .header(X_PROXY_BYTES, rewritten.len())
+                .header("server-timing", &server_timing)
+                .header(X_PROXY_FINAL_URL, source_url.as_str())
+                .body(Body::from(rewritten))
+                .unwrap_or_else(|_| {
+                    error::ProxyError::body_assembly().into_response()
+                });
+        }
+
+        let builder = Response::builder()
+            .status(status)
+            .header("content-type", proxied_content_type)
+            .header("cache-control", cache_control_header)
+            .header("CDN-Cache-Control", cdn_cache_control_header)
+            .header("content-length", rewritten.len())
+            .header(X_PROXY_BYTES, rewritten.len())
+            .header(header::ETAG, &etag)
+            .header("server-timing", &server_timing)
+            .header(X_PROXY_FINAL_URL, source_url.as_str());
+        let builder = append_set_cookies(builder, &headers_copy);
+        return copy_headers(builder, &state.config.copy_headers, &headers_copy)
+            .body(Body::from(rewritten))
+            .unwrap_or_else(|_| {
+                error::ProxyError::body_assembly().into_response()
+            });
+    }
+
+    // for binary .ts or other files, stream the body through as it arrives;
+    // an upstream error mid-stream aborts the client connection. Cacheable
+    // objects of known, bounded size are buffered instead so they can be
+    // stored, and those also tell how many bytes go out before they're sent.
+    // A body read into for sniffing carries on after the bytes already read.
+    let (head, upstream) = match upstream {
+        UpstreamBody::Peeked(head, res) => (Some(head), UpstreamBody::Live(res)),
+        upstream => (None, upstream),
+    };
+    let (body, proxied_bytes) = match upstream {
+        UpstreamBody::Cached(bytes) => {
+            state.metrics.add_bytes(bytes.len() as u64);
+            let len = bytes.len();
+            (Body::from(bytes), Some(len))
+        }
+        UpstreamBody::Buffered(bytes) => {
+            store(&bytes);
+            state.metrics.add_bytes(bytes.len() as u64);
+            let len = bytes.len();
+            (Body::from(bytes), Some(len))
+        }
+        UpstreamBody::Live(res)
+            if cache.zip(cache_ttl).is_some_and(|(cache, _)| {
+                res.content_length()
+                    .map_or(prefetch, |len| len as usize <= cache.max_entry_bytes())
+            }) =>
+        {
+            // a prefetched body of unknown length that outgrows the cache is dropped
+            let max_entry = cache.map_or(u64::MAX, |cache| cache.max_entry_bytes() as u64);
+            let limit = body_limit.map_or(max_entry, |limit| limit.min(max_entry));
+            match read_rest(head.map(Vec::from).unwrap_or_default(), res, Some(limit)).await {
+                Ok(bytes) => {
+                    store(&bytes);
+                    state.metrics.add_bytes(bytes.len() as u64);
+                    let len = bytes.len();
+                    (Body::from(bytes), Some(len))
+                }
+                Err(e) => return e.into_response(),
+            }
+        }
+        UpstreamBody::Live(res) => {
+            // bodies without a declared length are counted as they stream, and
+            // cut off once they pass the limit. The 200 is already sent when the
+            // upstream fails, resetting the connection makes the player retry
+            // instead of keeping a truncated segment
+            let metrics = state.metrics.clone();
+            let mut tally = StreamTally { url: cache_key.clone(), bytes: 0 };
+            let open = OpenStream::new(&state.streams);
+            let chunks = futures_util::stream::iter(head.map(Ok)).chain(res.bytes_stream());
+            let body = Body::from_stream(chunks.map(move |chunk| {
+                let _permit = &permit;
+                let _open = &open;
+                let chunk = chunk.inspect_err(|e| {
+                    error!(url = tally.url, bytes = tally.bytes, "upstream body failed mid-stream: {e}");
+                })?;
+                if let Some(limit) = body_limit
+                    && tally.sent(chunk.len()) > limit
+                {
+                    warn!(limit, "upstream body exceeded size limit mid-stream, aborting");
+                    return Err(BoxError::from("response body too large"));
+                }
+                metrics.add_bytes(chunk.len() as u64);
+                Ok(chunk)
+            }));
+            (body, None)
+        }
+        UpstreamBody::Peeked(..) => unreachable!("split into head and response above"),
+    };
+
+    let mut builder = Response::builder()
+        .status(status)
+        .header("content-type", proxied_content_type)
+        .header("cache-control", cache_control_header)
+        .header("CDN-Cache-Control", cdn_cache_control_header)
+        .header("server-timing", &server_timing)
+        .header(X_PROXY_FINAL_URL, source_url.as_str());
+    builder = append_set_cookies(builder, &headers_copy);
+    builder = copy_headers(builder, &state.config.copy_headers, &headers_copy);
+
+    // the length is known up front even though the body is streamed, and
+    // without one the client gets chunked encoding as the upstream did;
+    // range responses also need the range headers for the player to seek
+    for name in [header::CONTENT_LENGTH, header::ACCEPT_RANGES, header::CONTENT_RANGE] {
+        if let Some(value) = headers_copy.get(&name) {
+            builder = builder.header(name, value.clone());
+        }
+    }
+    if let Some(len) = proxied_bytes {
+        builder = builder.header(X_PROXY_BYTES, len);
+    }
+
+    builder
+        .body(body)
+        .unwrap_or_else(|_| {
+            error::ProxyError::body_assembly().into_response()
+        })
+}
+
+// Egress accounting for streamed bodies, whose size is only known once the
+// stream is done (or the client went away), so it's logged instead of sent in
+// X-Proxy-Bytes.
+struct StreamTally {
+    url: String,
+    bytes: u64,
+}
+
+impl StreamTally {
+    // through a method so the stream closure owns the whole tally, Drop included
+    fn sent(&mut self, len: usize) -> u64 {
+        self.bytes += len as u64;
+        self.bytes
+    }
+}
+
+impl Drop for StreamTally {
+    fn drop(&mut self) {
+        debug!(url = self.url, bytes = self.bytes, "streamed body finished");
+    }
+}
+
+// Counts a streamed body in AppState::open_streams for as long as it lives.
+struct OpenStream(Arc<AtomicUsize>);
+
+impl OpenStream {
+    fn new(streams: &Arc<AtomicUsize>) -> Self {
+        streams.fetch_add(1, Ordering::Relaxed);
+        OpenStream(streams.clone())
+    }
+}
+
+impl Drop for OpenStream {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+// Some CDNs serve gzipped playlists without a Content-Encoding, which reqwest
+// would otherwise have decoded. Those are recognized by the gzip magic bytes;
+// a body that doesn't decompress is rewritten as it came.
+fn gunzip_unlabelled(raw: Bytes, limit: Option<u64>) -> Result<Bytes, BodyError> {
+    use std::io::Read;
+
+    if !raw.starts_with(&[0x1f, 0x8b]) {
+        return Ok(raw);
+    }
+    let limit = limit.unwrap_or(u64::MAX);
+    let mut decoded = Vec::new();
+    match flate2::read::GzDecoder::new(&raw[..]).take(limit.saturating_add(1)).read_to_end(&mut decoded) {
+        Ok(_) if decoded.len() as u64 > limit => {
+            warn!(limit, "gzipped upstream body exceeds size limit once decompressed");
+            Err(BodyError::TooLarge)
+        }
+        Ok(_) => {
+            debug!(compressed = raw.len(), decoded = decoded.len(), "decompressed unlabelled gzip body");
+            Ok(Bytes::from(decoded))
+        }
+        Err(e) => {
+            warn!("body looks gzipped but doesn't decompress, using it as-is: {e}");
+            Ok(raw)
+        }
+    }
+}
+
+// Strong ETag of a rewritten body: its truncated SHA-256.
+fn playlist_etag(body: &str) -> String {
+    use base64::Engine;
+    use sha2::Digest;
+
+    let digest = sha2::Sha256::digest(body.as_bytes());
+    format!("\"{}\"", base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(&digest[..16]))
+}
+
+// Whether the client's If-None-Match lists `etag`, compared weakly as
+// RFC 9110 asks.
+fn if_none_match(client_headers: &HeaderMap, etag: &str) -> bool {
+    client_headers
+        .get_all(header::IF_NONE_MATCH)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+// Decodes a text body for rewriting. Anything not valid UTF-8 is taken as
+// Latin-1 when the Content-Type says so and decoded lossily otherwise, so a
+// misconfigured origin still gets a playable, if slightly mangled, playlist.
+fn decode_text(raw: &[u8], content_type: &str) -> String {
+    if let Ok(text) = std::str::from_utf8(raw) {
+        return text.to_string();
+    }
+    let charset = content_type
+        .split(';')
+        .filter_map(|param| param.trim().split_once('='))
+        .find(|(name, _)| name.eq_ignore_ascii_case("charset"))
+        .map(|(_, value)| value.trim_matches('"').to_ascii_lowercase());
+    warn!(charset = charset.as_deref().unwrap_or("none"), "upstream text body is not valid UTF-8");
+    match charset.as_deref() {
+        // every Latin-1 byte is the code point of the same value
+        Some("iso-8859-1" | "latin1" | "latin-1" | "us-ascii") => raw.iter().map(|&b| b as char).collect(),
+        _ => String::from_utf8_lossy(raw).into_owned(),
+    }
+}
+
+// Playlists, manifests and subtitles by Content-Type or extension, the
+// bodies that get rewritten.
+fn is_text_format(headers: &HeaderMap, url: &url::Url) -> bool {
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("");
+    ["application/vnd.apple.mpegurl", "application/dash+xml", "text/vtt"].iter().any(|t| content_type.contains(t))
+        || [".m3u8", ".mpd", ".vtt"].iter().any(|ext| url.path().ends_with(ext))
+}
+
+// Whether `body` will be rewritten when served, sniffed playlists included.
+fn is_rewritten(headers: &HeaderMap, url: &url::Url, body: &[u8]) -> bool {
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or("text/plain");
+    is_text_format(headers, url) || is_generic_content_type(content_type) && hls::looks_like_playlist(body)
+}
+
+// Content types that say nothing about the body, worth sniffing.
+fn is_generic_content_type(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or("").trim().to_ascii_lowercase();
+    matches!(essence.as_str(), "application/octet-stream" | "binary/octet-stream" | "text/plain" | "")
+}
+
+// How much of a body of unknown length is read to tell whether it's a playlist.
+const SNIFF_BYTES: usize = 512;
+
+// Media segment extensions, never sniffed for a playlist.
+const SEGMENT_EXTENSIONS: &[&str] = &[".ts", ".m4s", ".mp4", ".m4a", ".m4v", ".aac", ".key"];
+
+// the upstream URL that served the response, after redirects and fallback;
+// strip it with PROXY_STRIP_HEADERS if redirect targets carry secrets
+const X_PROXY_FINAL_URL: &str = "x-proxy-final-url";
+
+// body bytes sent for the request, for per-request egress accounting. It's
+// the uncompressed payload size: set before CompressionLayer, so a gzipped
+// playlist reports its rewritten length, not what went over the wire
+const X_PROXY_BYTES: &str = "x-proxy-bytes";
+
+// whether the response came from the cache, CDN style
+const X_PROXY_CACHE: &str = "x-proxy-cache";
+
+// HIT carries the age of the stored entry, sent as Age. BYPASS covers
+// requests the cache can't serve (HEAD, ranges, follow_redirects=0, cache
+// off) and responses it won't store.
+#[derive(Clone, Copy)]
+enum CacheOutcome {
+    Hit(Duration),
+    // an expired entry served because the upstream failed, see stale-if-error
+    Stale(Duration),
+    Miss,
+    Bypass,
+}
+
+impl CacheOutcome {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Hit(_) => "HIT",
+            Self::Stale(_) => "STALE",
+            Self::Miss => "MISS",
+            Self::Bypass => "BYPASS",
+        }
+    }
+}
+
+const LIVE_PLAYLIST_CACHE_CONTROL: &str = "max-age=2";
+
+// Cache-Control for a response fetched with the client's credentials: the
+// same lifetimes, but only the client itself may keep it.
+fn private_cache_control(cache_control: &str) -> String {
+    let directives: Vec<&str> = cache_control
+        .split(',')
+        .map(str::trim)
+        .filter(|d| !d.is_empty() && !d.eq_ignore_ascii_case("public") && !d.eq_ignore_ascii_case("private"))
+        .collect();
+    std::iter::once("private").chain(directives).collect::<Vec<_>>().join(", ")
+}
+const ERROR_CACHE_CONTROL: &str = "no-store";
+
+// Token-gated CDNs set cookies on the manifest that the player has to echo on
+// segment requests; there can be several, so each one is appended.
+fn append_set_cookies(
+    mut builder: axum::http::response::Builder,
+    upstream_headers: &HeaderMap,
+) -> axum::http::response::Builder {
+    for value in upstream_headers.get_all(header::SET_COOKIE) {
+        builder = builder.header(header::SET_COOKIE, value.clone());
+    }
+    builder
+}
+
+// PROXY_COPY_HEADERS, e.g. request ids for debugging. Headers the proxy
+// already set itself win.
+fn copy_headers(
+    mut builder: axum::http::response::Builder,
+    names: &[header::HeaderName],
+    upstream_headers: &HeaderMap,
+) -> axum::http::response::Builder {
+    for name in names {
+        if builder.headers_ref().is_some_and(|set| set.contains_key(name)) {
+            continue;
+        }
+        for value in upstream_headers.get_all(name) {
+            builder = builder.header(name, value.clone());
+        }
+    }
+    builder
+}
+
+// Every FetchQuery field is optional, so extraction only fails on malformed
+// query strings, e.g. a repeated parameter. Those get a ProxyError rather than
+// axum's own rejection text.
+fn fetch_query(params: Result<Query<FetchQuery>, QueryRejection>) -> Result<FetchQuery, error::ProxyError> {
+    params.map(|Query(mut params)| {
+        params.ref_ = params.ref_.or(params.ref_alias.take());
+        params
+    }).map_err(|e| {
+        error::ProxyError::new(
+            StatusCode::BAD_REQUEST,
+            "INVALID_QUERY",
+            format!("Invalid query string: {}", e.body_text())
+        )
+    })
+}
+
+// Whether `url` is DASH URL template `template` filled in, on the template's
+// own host whatever the substituted values are.
+fn template_covers(template: &str, url: &str) -> bool {
+    let host = |url: &str| url::Url::parse(url).ok().map(|url| (url.scheme().to_string(), url.host_str().map(str::to_string), url.port()));
+    links::matches_template(template, url) && host(template).is_some_and(|template| host(url) == Some(template))
+}
+
+// The upstream URL a /fetch-style query points at, from `b64` or `url`,
+// signature-checked when signing is on and vetted by `check_target`.
+async fn resolve_target(state: &AppState, params: &FetchQuery) -> Result<url::Url, error::ProxyError> {
+    let target = match (&params.b64, &params.url) {
+        (Some(b64), _) => links::decode_b64_url(b64).ok_or_else(|| {
+            error::ProxyError::new(StatusCode::BAD_REQUEST, "INVALID_B64", "Invalid b64 parameter")
+        })?,
+        (None, Some(url)) => url.clone(),
+        (None, None) => return Err(error::ProxyError::new(
+            StatusCode::BAD_REQUEST,
+            "MISSING_URL",
+            "Missing required 'url' query parameter"
+        )),
+    };
+
+    let max_len = state.config.max_url_len;
+    if max_len > 0 && target.len() > max_len {
+        let prefix: String = target.chars().take(64).collect();
+        debug!(len = target.len(), limit = max_len, %prefix, "upstream URL too long");
+        return Err(error::ProxyError::new(
+            StatusCode::URI_TOO_LONG,
+            "URL_TOO_LONG",
+            format!("Upstream URL longer than {max_len} bytes")
+        ));
+    }
+
+    // a filled-in template link is signed over its template
+    let signed = match &params.tpl {
+        Some(tpl) => links::decode_b64_url(tpl).filter(|template| template_covers(template, &target)),
+        None => Some(target.clone()),
+    };
+    if let Some(key) = &state.config.signing_key
+        && !params.sig.as_deref().zip(signed).is_some_and(|(sig, signed)| links::verify_signature(key, &signed, sig))
+    {
+        return Err(error::ProxyError::new(
+            StatusCode::FORBIDDEN,
+            "INVALID_SIGNATURE",
+            "Missing or invalid signature"
+        ));
+    }
+
+    let mut parsed = url::Url::parse(&target)
+        .map_err(|_| error::ProxyError::new(StatusCode::BAD_REQUEST, "INVALID_URL", "Invalid URL"))?;
+    // before the checks, a transform may well move the URL to another host
+    state.config.url_transforms.apply(&mut parsed);
+    tracing::Span::current().record("url", cache::normalize_url(&parsed, state.config.sort_query).as_str());
+    check_target(state, &parsed).await?;
+    Ok(parsed)
+}
+
+// The `ua` parameter when given, PROXY_USER_AGENT or the built-in default otherwise.
+fn user_agent(state: &AppState, params: &FetchQuery) -> Result<HeaderValue, error::ProxyError> {
+    let Some(ua) = &params.ua else {
+        return Ok(state.config.user_agent.clone());
+    };
+    // HeaderValue lets tabs and obs-text through, neither belongs in a UA
+    if ua.chars().any(char::is_control) {
+        return Err(error::ProxyError::new(StatusCode::BAD_REQUEST, "INVALID_USER_AGENT", "Invalid ua parameter"));
+    }
+    HeaderValue::from_str(ua)
+        .map_err(|_| error::ProxyError::new(StatusCode::BAD_REQUEST, "INVALID_USER_AGENT", "Invalid ua parameter"))
+}
+
+// A `ct` parameter, which has to be a usable header value.
+fn forced_content_type(ct: &str) -> Result<String, error::ProxyError> {
+    let invalid = || error::ProxyError::new(StatusCode::BAD_REQUEST, "INVALID_CONTENT_TYPE", "Invalid ct parameter");
+    let ct = ct.trim();
+    if ct.is_empty() || ct.chars().any(char::is_control) {
+        return Err(invalid());
+    }
+    HeaderValue::from_str(ct).map_err(|_| invalid())?;
+    Ok(ct.to_string())
+}
+
+// `Authorization: Bearer <auth>` for an `auth` parameter, which wins over an
+// Authorization header from the client.
+fn bearer_authorization(params: &FetchQuery) -> Result<Option<HeaderValue>, error::ProxyError> {
+    let Some(token) = &params.auth else {
+        return Ok(None);
+    };
+    let mut value = HeaderValue::from_str(&format!("Bearer {token}"))
+        .map_err(|_| error::ProxyError::new(StatusCode::BAD_REQUEST, "INVALID_AUTH", "Invalid auth parameter"))?;
+    value.set_sensitive(true);
+    Ok(Some(value))
+}
+
+// `1` or `0` from a query parameter, anything else left to the default.
+fn query_flag(value: &Option<String>) -> Option<bool> {
+    match value.as_deref() {
+        Some("1") => Some(true),
+        Some("0") => Some(false),
+        _ => None,
+    }
+}
+
+// Which of the derived Referer / Origin headers stay out of the upstream
+// request, for origins that reject ones they don't recognise.
+#[derive(Clone, Copy, Default)]
+struct OmittedHeaders {
+    referer: bool,
+    origin: bool,
+}
+
+fn omitted_headers(state: &AppState, params: &FetchQuery) -> OmittedHeaders {
+    OmittedHeaders {
+        referer: query_flag(&params.no_referer).unwrap_or(state.config.no_referer),
+        origin: query_flag(&params.no_origin).unwrap_or(state.config.no_origin),
+    }
+}
+
+// Headers sent upstream for `url`: the User-Agent, Referer and Origin, plus
+// Range and the FORWARDED_CLIENT_HEADERS taken from the client request.
+fn build_upstream_headers(
+    url: &url::Url,
+    referer: Option<&str>,
+    user_agent: HeaderValue,
+    client_headers: &HeaderMap,
+    omitted: OmittedHeaders,
+) -> reqwest_header::HeaderMap {
+    let mut headers = reqwest_header::HeaderMap::new();
+    headers.insert(reqwest_header::USER_AGENT, user_agent);
+    if !omitted.referer {
+        let ref_header = referer
+            .map(str::to_string)
+            .unwrap_or_else(|| url.origin().ascii_serialization());
+        headers.insert(
+            reqwest_header::REFERER,
+            HeaderValue::from_str(&ref_header).unwrap_or(HeaderValue::from_static("")),
+        );
+    }
+    headers.insert(
+        reqwest_header::ACCEPT,
+        HeaderValue::from_static("*/*"),
+    );
+
+    // forward the player's Range so seeking works, otherwise .ts segments might need one
+    if let Some(range) = client_headers.get(header::RANGE) {
+        headers.insert(reqwest_header::RANGE, range.clone());
+    } else if url.path().ends_with(".ts") {
+        headers.insert(
+            reqwest_header::RANGE,
+            HeaderValue::from_static("bytes=0-"),
+        );
+    }
+
+    // conditional and content-negotiation headers from the player, never
+    // replacing the ones the proxy sets itself
+    for name in FORWARDED_CLIENT_HEADERS {
+        if let Some(value) = client_headers.get(&name)
+            && !headers.contains_key(&name)
+        {
+            let mut value = value.clone();
+            // credentials show up as "Sensitive" if the headers ever get logged
+            if name == header::AUTHORIZATION || name == header::COOKIE {
+                value.set_sensitive(true);
+            }
+            headers.insert(name, value);
+        }
+    }
+
+    // add Origin header; IP-literal hosts keep their scheme and port, and
+    // IPv6 ones their brackets
+    let origin_header = match url.domain() {
+        _ if omitted.origin => None,
+        Some(domain) => Some(format!("https://{}", domain)),
+        None if url.host_str().is_some() => Some(url.origin().ascii_serialization()),
+        None => None,
+    };
+    if let Some(origin_header) = origin_header {
+        headers.insert(
+            reqwest_header::ORIGIN,
+            HeaderValue::from_str(&origin_header).unwrap_or(HeaderValue::from_static("")),
+        );
+    }
+
+    headers
+}
+
+// Scheme, allowlist and SSRF checks every upstream URL has to pass.
+async fn check_target(state: &AppState, url: &url::Url) -> Result<(), error::ProxyError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(error::ProxyError::new(
+            StatusCode::BAD_REQUEST,
+            "UNSUPPORTED_SCHEME",
+            format!("Unsupported URL scheme '{}', only http and https are allowed", url.scheme())
+        ));
+    }
+
+    if let Some(allowed) = &state.config.allowed_hosts
+        && !url.host_str().is_some_and(|host| allowed.allows(host))
+    {
+        return Err(error::ProxyError::new(
+            StatusCode::FORBIDDEN,
+            "HOST_NOT_ALLOWED",
+            "Host not allowed"
+        ));
+    }
+
+    if state.config.block_private_addresses
+        && let Err(e) = ssrf::check_url(url).await
+    {
+        return Err(error::ProxyError::new(
+            StatusCode::FORBIDDEN,
+            "FORBIDDEN_TARGET",
+            format!("Forbidden target: {e}")
+        ));
+    }
+
+    Ok(())
+}
+
+// The primary URL's path and query on the fallback origin, below the
+// fallback's own path prefix if it has one.
+fn fallback_url(base: &url::Url, primary: &url::Url) -> url::Url {
+    let mut alternate = base.clone();
+    alternate.set_path(&format!("{}{}", base.path().trim_end_matches('/'), primary.path()));
+    alternate.set_query(primary.query());
+    alternate
+}
+
+const MAX_REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+// A `timeout` parameter in whole seconds; zero and non-numbers are rejected,
+// anything above MAX_REQUEST_TIMEOUT is clamped to it.
+fn parse_timeout(value: &str) -> Option<Duration> {
+    let secs = value.parse::<u64>().ok().filter(|&secs| secs > 0)?;
+    Some(Duration::from_secs(secs).min(MAX_REQUEST_TIMEOUT))
+}
+
+// A `start` offset in seconds, fractions allowed.
+fn parse_start(value: &str) -> Option<f64> {
+    value.trim().parse::<f64>().ok().filter(|secs| secs.is_finite() && *secs >= 0.0)
+}
+
+const RETRY_BASE_DELAY: Duration = Duration::from_millis(100);
+// where the doubling stops, however many retries PROXY_RETRIES allows
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5);
+
+// Backoff before retry number `attempt`, counting from 0.
+fn retry_delay(attempt: u32) -> Duration {
+    RETRY_BASE_DELAY.saturating_mul(2u32.saturating_pow(attempt)).min(MAX_RETRY_DELAY)
+}
+
+// Sends the upstream request, retrying connection failures and 502/503/504
+// responses with exponential backoff. Nothing of a retried response's body
+// has been read at that point, and 4xx or timeouts are never retried.
+async fn send_with_retries(
+    state: &AppState,
+    follow_redirects: bool,
+    method: Method,
+    url: &url::Url,
+    headers: &reqwest_header::HeaderMap,
+    timeout: Option<Duration>,
+) -> reqwest::Result<reqwest::Response> {
+    let mut attempt = 0;
+    let mut waited_out_throttling = false;
+    loop {
+        let client = if follow_redirects { &state.client } else { &state.no_redirect_client };
+        let mut request = client
+            .request(method.clone(), url.clone())
+            .headers(headers.clone());
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
+        let result = request.send().await;
+
+        // brief origin throttling is waited out once, on top of the retries
+        if let Ok(res) = &result
+            && !waited_out_throttling
+            && let Some(wait) = throttled_wait(res, state.config.max_retry_after)
+        {
+            waited_out_throttling = true;
+            warn!("upstream rate limited, retrying in {wait:?}");
+            tokio::time::sleep(wait).await;
+            continue;
+        }
+
+        let retryable = match &result {
+            Ok(res) => matches!(
+                res.status(),
+                StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+            ),
+            Err(e) => e.is_connect() && !ssrf::is_blocked_error(e),
+        };
+        if !retryable || attempt >= state.config.retries {
+            return result;
+        }
+
+        let delay = retry_delay(attempt);
+        attempt += 1;
+        warn!(attempt, "transient upstream failure, retrying in {delay:?}");
+        tokio::time::sleep(delay).await;
+    }
+}
+
+// How long to wait before retrying a 429, if its Retry-After is given in
+// seconds and no longer than `max`. A little jitter keeps the clients the
+// origin throttled together from all coming back at once.
+fn throttled_wait(res: &reqwest::Response, max: Duration) -> Option<Duration> {
+    if res.status() != StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+    let secs: u64 = res.headers().get(header::RETRY_AFTER)?.to_str().ok()?.trim().parse().ok()?;
+    let wait = Duration::from_secs(secs);
+    if wait > max || max.is_zero() {
+        return None;
+    }
+    let nanos = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().subsec_nanos();
+    Some(wait + Duration::from_millis(u64::from(nanos) % 250))
+}
+
+// Largest response shared with coalesced requests that isn't a playlist or
+// subtitle, those are buffered anyway. Anything bigger is streamed to the
+// leader alone so it doesn't wait for the whole body.
+const MAX_SHARED_BYTES: u64 = 16 * 1024 * 1024;
+
+// The body limit to buffer a response under for sharing, or None if it isn't
+// shared: only 200s without cookies, of a size worth holding. Other bodies are
+// only held back from streaming when `followers` are already waiting for them.
+fn share_limit(config: &config::Config, res: &reqwest::Response, followers: bool) -> Option<Option<u64>> {
+    if res.status() != StatusCode::OK || res.headers().contains_key(header::SET_COOKIE) {
+        return None;
+    }
+    if is_text_format(res.headers(), res.url()) {
+        return Some(Some(config.max_text_bytes));
+    }
+    let len = res.content_length().filter(|_| followers)?;
+    (len <= MAX_SHARED_BYTES && config.max_body_bytes.is_none_or(|max| len <= max)).then_some(config.max_body_bytes)
+}
+
+// Everything about an upstream response's headers is copied around, so an
+// origin sending thousands of them, or huge ones, is treated as broken.
+fn check_upstream_headers(config: &config::Config, res: &reqwest::Response) -> Result<(), error::ProxyError> {
+    let headers = res.headers();
+    let bytes: usize = headers.iter().map(|(name, value)| name.as_str().len() + value.len()).sum();
+    if headers.len() <= config.max_upstream_headers && bytes <= config.max_upstream_header_bytes {
+        return Ok(());
+    }
+    warn!(url = %res.url(), count = headers.len(), bytes, "upstream response headers over the limit");
+    Err(error::ProxyError::new(
+        StatusCode::BAD_GATEWAY,
+        "UPSTREAM_HEADERS_TOO_LARGE",
+        "Upstream response headers too large"
+    ))
+}
+
+// An upstream redirect leading off PROXY_ALLOWED_HOSTS, refused like a
+// request for that host would have been.
+fn redirect_not_allowed() -> error::ProxyError {
+    error::ProxyError::new(
+        StatusCode::FORBIDDEN,
+        "HOST_NOT_ALLOWED",
+        "Host not allowed: redirected outside the allowlist"
+    )
+}
+
+// Timeouts become 504 and every other upstream failure 502, so clients and
+// CDNs can tell them apart from our own 500s.
+fn upstream_error(e: &reqwest::Error) -> error::ProxyError {
+    let (status, code) = if e.is_timeout() {
+        (StatusCode::GATEWAY_TIMEOUT, "UPSTREAM_TIMEOUT")
+    } else {
+        (StatusCode::BAD_GATEWAY, "UPSTREAM_ERROR")
+    };
+    error::ProxyError::new(status, code, format!("Fetch failed: {e}"))
+}
+
+// Where the upstream body comes from: the origin, or the in-memory cache.
+enum UpstreamBody {
+    Live(reqwest::Response),
+    // from the origin, its first bytes already read to sniff the type
+    Peeked(Bytes, reqwest::Response),
+    Cached(Bytes),
+    // fresh from the origin but already read, to be shared with coalesced requests
+    Buffered(Bytes),
+}
+
+impl UpstreamBody {
+    async fn bytes(self, limit: Option<u64>) -> Result<Bytes, BodyError> {
+        match self {
+            UpstreamBody::Live(res) => read_body(res, limit).await,
+            UpstreamBody::Peeked(head, res) => read_rest(head.into(), res, limit).await,
+            UpstreamBody::Cached(bytes) | UpstreamBody::Buffered(bytes) => Ok(bytes),
+        }
+    }
+}
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+enum BodyError {
+    TooLarge,
+    Upstream(reqwest::Error),
+}
+
+impl IntoResponse for BodyError {
+    fn into_response(self) -> Response {
+        match self {
+            BodyError::TooLarge => payload_too_large().into_response(),
+            BodyError::Upstream(e) => {
+                error!("proxy error: {e:?}");
+                upstream_error(&e).into_response()
+            }
+        }
+    }
+}
+
+// Buffers an upstream body, giving up as soon as it grows past `limit`.
+async fn read_body(res: reqwest::Response, limit: Option<u64>) -> Result<Bytes, BodyError> {
+    read_rest(Vec::new(), res, limit).await
+}
+
+// Like read_body, for a body whose first bytes are already in `buf`.
+async fn read_rest(mut buf: Vec<u8>, mut res: reqwest::Response, limit: Option<u64>) -> Result<Bytes, BodyError> {
+    while let Some(chunk) = res.chunk().await.map_err(BodyError::Upstream)? {
+        if limit.is_some_and(|limit| (buf.len() + chunk.len()) as u64 > limit) {
+            warn!(limit, "upstream body exceeds size limit");
+            return Err(BodyError::TooLarge);
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(Bytes::from(buf))
+}
+
+// Reads until at least `len` bytes are in, or the body ends, which the flag
+// tells.
+async fn read_prefix(res: &mut reqwest::Response, len: usize) -> Result<(Vec<u8>, bool), BodyError> {
+    let mut buf = Vec::new();
+    while buf.len() < len {
+        match res.chunk().await.map_err(BodyError::Upstream)? {
+            Some(chunk) => buf.extend_from_slice(&chunk),
+            None => return Ok((buf, true)),
+        }
+    }
+    Ok((buf, false))
+}
+
+fn payload_too_large() -> error::ProxyError {
+    error::ProxyError::new(
+        StatusCode::PAYLOAD_TOO_LARGE,
+        "PAYLOAD_TOO_LARGE",
+        "Upstream response too large"
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+    use std::net::SocketAddr;
+    use tower::ServiceExt;
+
+    fn test_state() -> AppState {
+        AppState::new(config::Config {
+            block_private_addresses: false,
+            ..Default::default()
+        })
+    }
+
+    // Serves `origin` on an ephemeral local port and returns its address.
+    async fn spawn_origin(origin: Router) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, origin).await.unwrap() });
+        addr
+    }
+
+    async fn proxy(state: AppState, request: Request<Body>) -> Response {
+        build_app(state).oneshot(request).await.unwrap()
+    }
+
+    fn fetch_request(target: &str) -> axum::http::request::Builder {
+        Request::get(format!("/fetch?url={}", urlencoding::encode(target)))
+    }
+
+    #[tokio::test]
+    async fn range_request_passes_partial_content_through() {
+        let origin = Router::new().route(
+            "/video.mp4",
+            get(|headers: HeaderMap| async move {
+                assert_eq!(headers[header::RANGE], "bytes=100-199");
+                (
+                    StatusCode::PARTIAL_CONTENT,
+                    [
+                        (header::CONTENT_TYPE, "video/mp4"),
+                        (header::ACCEPT_RANGES, "bytes"),
+                        (header::CONTENT_RANGE, "bytes 100-199/1000"),
+                    ],
+                    vec![0u8; 100],
+                )
+            }),
+        );
+        let addr = spawn_origin(origin).await;
+
+        let request = fetch_request(&format!("http://{addr}/video.mp4"))
+            .header(header::RANGE, "bytes=100-199")
+            .body(Body::empty())
+            .unwrap();
+        let res = proxy(test_state(), request).await;
+
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.headers()[header::ACCEPT_RANGES], "bytes");
+        assert_eq!(res.headers()[header::CONTENT_RANGE], "bytes 100-199/1000");
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body.len(), 100);
+    }
+
+    #[tokio::test]
+    async fn cached_segment_skips_the_origin() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let origin = Router::new().route(
+            "/seg.ts",
+            get(move || async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                ([(header::CACHE_CONTROL, "max-age=60")], "segment-bytes")
+            }),
+        );
+        let addr = spawn_origin(origin).await;
+
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            cache_bytes: 1 << 20,
+            ..Default::default()
+        });
+        for _ in 0..2 {
+            let request = fetch_request(&format!("http://{addr}/seg.ts"))
+                .body(Body::empty())
+                .unwrap();
+            let res = proxy(state.clone(), request).await;
+            let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(&body[..], b"segment-bytes");
+        }
+
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn upstream_timeout_returns_gateway_timeout() {
+        let origin = Router::new().route(
+            "/slow",
+            get(|| async {
+                tokio::time::sleep(Duration::from_secs(30)).await;
+                "too late"
+            }),
+        );
+        let addr = spawn_origin(origin).await;
+
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            timeout: Duration::from_millis(100),
+            ..Default::default()
+        });
+        let request = fetch_request(&format!("http://{addr}/slow")).body(Body::empty()).unwrap();
+        let res = proxy(state, request).await;
+
+        assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn unreachable_upstream_returns_bad_gateway() {
+        // grab a free port and close it again so nothing is listening
+        let addr = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+
+        let request = fetch_request(&format!("http://{addr}/gone")).body(Body::empty()).unwrap();
+        let res = proxy(test_state(), request).await;
+
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn transient_upstream_errors_are_retried() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let origin = Router::new().route(
+            "/flaky.ts",
+            get(move || async move {
+                if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                    (StatusCode::SERVICE_UNAVAILABLE, "try again")
+                } else {
+                    (StatusCode::OK, "segment")
+                }
+            }),
+        );
+        let addr = spawn_origin(origin).await;
+
+        let request = fetch_request(&format!("http://{addr}/flaky.ts")).body(Body::empty()).unwrap();
+        let res = proxy(test_state(), request).await;
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn head_is_forwarded_without_a_body() {
+        let origin = Router::new().route(
+            "/video.mp4",
+            get(|| async { "unexpected" }).head(|| async {
+                (
+                    [
+                        (header::CONTENT_TYPE, "video/mp4"),
+                        (header::CONTENT_LENGTH, "1234"),
+                        (header::ACCEPT_RANGES, "bytes"),
+                    ],
+                    Body::empty(),
+                )
+            }),
+        );
+        let addr = spawn_origin(origin).await;
+
+        let request = fetch_request(&format!("http://{addr}/video.mp4"))
+            .method(Method::HEAD)
+            .body(Body::empty())
+            .unwrap();
+        let res = proxy(test_state(), request).await;
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "video/mp4");
+        assert_eq!(res.headers()[header::CONTENT_LENGTH], "1234");
+        assert_eq!(res.headers()[header::ACCEPT_RANGES], "bytes");
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn gzipped_playlist_is_decoded_before_rewriting() {
+        use flate2::{Compression, write::GzEncoder};
+        use std::io::Write;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"#EXTM3U\n#EXTINF:4.0,\nseg-1.ts\n").unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        let origin = Router::new().route(
+            "/live/index.m3u8",
+            get(move || async move {
+                (
+                    [
+                        (header::CONTENT_TYPE, "application/vnd.apple.mpegurl"),
+                        (header::CONTENT_ENCODING, "gzip"),
+                    ],
+                    gzipped,
+                )
+            }),
+        );
+        let addr = spawn_origin(origin).await;
+
+        let request = fetch_request(&format!("http://{addr}/live/index.m3u8")).body(Body::empty()).unwrap();
+        let res = proxy(test_state(), request).await;
+
+        assert!(res.headers().get(header::CONTENT_ENCODING).is_none());
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let segment = url::Url::parse(&format!("http://{addr}/live/seg-1.ts")).unwrap();
+        assert_eq!(
+            String::from_utf8(body.to_vec()).unwrap(),
+            format!("#EXTM3U\n#EXTINF:4.0,\n{}", links::Links::default().with_depth(1).link(&segment))
+        );
+    }
+
+    #[tokio::test]
+    async fn signing_mode_requires_valid_sig_and_signs_emitted_links() {
+        let origin = Router::new().route(
+            "/index.m3u8",
+            get(|| async { "#EXTM3U\n#EXTINF:4.0,\nseg-1.ts" }),
+        );
+        let addr = spawn_origin(origin).await;
+        let target = format!("http://{addr}/index.m3u8");
+        let key = b"secret".to_vec();
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            signing_key: Some(key.clone()),
+            ..Default::default()
+        });
+
+        let unsigned = fetch_request(&target).body(Body::empty()).unwrap();
+        assert_eq!(proxy(state.clone(), unsigned).await.status(), StatusCode::FORBIDDEN);
+
+        let uri = format!(
+            "/fetch?url={}&sig={}",
+            urlencoding::encode(&target),
+            links::sign_url(&key, &target)
+        );
+        let res = proxy(state.clone(), Request::get(uri).body(Body::empty()).unwrap()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8(body.to_vec()).unwrap();
+        let segment = format!("http://{addr}/seg-1.ts");
+        assert!(body.ends_with(&format!("&sig={}&depth=1", links::sign_url(&key, &segment))));
+
+        // the signature doesn't cover a fallback or pin_host, so neither can be added
+        for extra in ["&fallback=http%3A%2F%2Fattacker.example%2Fprefix%2F", "&pin_host=attacker.example"] {
+            let uri = format!(
+                "/fetch?url={}&sig={}{extra}",
+                urlencoding::encode(&target),
+                links::sign_url(&key, &target),
+            );
+            let res = proxy(state.clone(), Request::get(uri).body(Body::empty()).unwrap()).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN, "{extra}");
+        }
+    }
+
+    #[tokio::test]
+    async fn not_modified_is_passed_through() {
+        let origin = Router::new().route(
+            "/index.m3u8",
+            get(|headers: HeaderMap| async move {
+                assert_eq!(headers[header::IF_NONE_MATCH], "\"v1\"");
+                (
+                    StatusCode::NOT_MODIFIED,
+                    [(header::ETAG, "\"v1\""), (header::CACHE_CONTROL, "max-age=5")],
+                )
+            }),
+        );
+        let addr = spawn_origin(origin).await;
+
+        let request = fetch_request(&format!("http://{addr}/index.m3u8"))
+            .header(header::IF_NONE_MATCH, "\"v1\"")
+            .body(Body::empty())
+            .unwrap();
+        let res = proxy(test_state(), request).await;
+
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(res.headers()[header::ETAG], "\"v1\"");
+        assert_eq!(res.headers()[header::CACHE_CONTROL], "max-age=5");
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert!(body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn concurrency_limit_rejects_and_releases_permits() {
+        let origin = Router::new().route("/seg.ts", get(|| async { "segment" }));
+        let addr = spawn_origin(origin).await;
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            max_concurrency: Some(1),
+            ..Default::default()
+        });
+        let target = format!("http://{addr}/seg.ts");
+
+        // a streamed response holds its permit until the body is dropped
+        let first = proxy(state.clone(), fetch_request(&target).body(Body::empty()).unwrap()).await;
+        let second = proxy(state.clone(), fetch_request(&target).body(Body::empty()).unwrap()).await;
+        assert_eq!(second.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(second.headers()[header::RETRY_AFTER], "1");
+
+        drop(first);
+        let third = proxy(state, fetch_request(&target).body(Body::empty()).unwrap()).await;
+        assert_eq!(third.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn upstream_requests_go_through_configured_proxy() {
+        // plain HTTP proxies receive absolute-form requests, which axum routes by path
+        let forward_proxy = Router::new().fallback(|headers: HeaderMap, uri: axum::http::Uri| async move {
+            let auth = headers
+                .get(header::PROXY_AUTHORIZATION)
+                .map(|v| v.to_str().unwrap().to_string())
+                .unwrap_or_default();
+            format!("{uri} {auth}")
+        });
+        let addr = spawn_origin(forward_proxy).await;
+
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            upstream_proxy: Some(url::Url::parse(&format!("http://user:pass@{addr}")).unwrap()),
+            ..Default::default()
+        });
+        let request = fetch_request("http://origin.invalid/seg.bin").body(Body::empty()).unwrap();
+        let res = proxy(state, request).await;
+
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"http://origin.invalid/seg.bin Basic dXNlcjpwYXNz");
+    }
+
+    async fn body_text(res: Response) -> String {
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn non_http_schemes_are_rejected() {
+        for target in ["file:///etc/passwd", "data:text/plain;base64,aGVsbG8=", "ftp://example.com/a.ts"] {
+            let res = proxy(test_state(), fetch_request(target).body(Body::empty()).unwrap()).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{target}");
+            assert!(body_text(res).await.starts_with("Unsupported URL scheme"), "{target}");
+        }
+    }
+
+    #[tokio::test]
+    async fn relative_url_is_rejected() {
+        let res = proxy(test_state(), fetch_request("/video/seg.ts").body(Body::empty()).unwrap()).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_text(res).await, "Invalid URL");
+    }
+
+    #[tokio::test]
+    async fn oversized_bodies_are_refused() {
+        let origin = Router::new()
+            .route("/big.mp4", get(|| async { vec![0u8; 4096] }))
+            .route(
+                "/chunked.mp4",
+                get(|| async {
+                    let chunks = futures_util::stream::iter(
+                        (0..4).map(|_| Ok::<_, std::io::Error>(Bytes::from(vec![0u8; 1024]))),
+                    );
+                    Body::from_stream(chunks)
+                }),
+            );
+        let addr = spawn_origin(origin).await;
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            max_body_bytes: Some(2048),
+            ..Default::default()
+        });
+
+        // a declared Content-Length over the limit is refused up front
+        let request = fetch_request(&format!("http://{addr}/big.mp4")).body(Body::empty()).unwrap();
+        let res = proxy(state.clone(), request).await;
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // a chunked body is cut off once it passes the limit
+        let request = fetch_request(&format!("http://{addr}/chunked.mp4")).body(Body::empty()).unwrap();
+        let res = proxy(state, request).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(axum::body::to_bytes(res.into_body(), usize::MAX).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn missing_segment_is_fetched_from_fallback() {
+        let primary = spawn_origin(Router::new()).await;
+        let mirror = spawn_origin(Router::new().route("/mirror/v/seg.ts", get(|| async { "from-mirror" }))).await;
+
+        let uri = format!(
+            "/fetch?url={}&fallback={}",
+            urlencoding::encode(&format!("http://{primary}/v/seg.ts")),
+            urlencoding::encode(&format!("http://{mirror}/mirror/")),
+        );
+        let res = proxy(test_state(), Request::get(uri).body(Body::empty()).unwrap()).await;
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body_text(res).await, "from-mirror");
+    }
+
+    #[tokio::test]
+    async fn errors_are_json_when_asked_for() {
+        let plain = proxy(test_state(), fetch_request("not a url").body(Body::empty()).unwrap()).await;
+        assert_eq!(plain.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_text(plain).await, "Invalid URL");
+
+        let request = fetch_request("not a url")
+            .header(header::ACCEPT, "application/json")
+            .body(Body::empty())
+            .unwrap();
+        let res = proxy(test_state(), request).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(body_text(res).await, r#"{"error":"Invalid URL","code":"INVALID_URL"}"#);
+    }
+
+    #[tokio::test]
+    async fn timeout_parameter_overrides_the_default() {
+        let origin = spawn_origin(Router::new().route(
+            "/slow.ts",
+            get(|| async {
+                tokio::time::sleep(Duration::from_secs(2)).await;
+                "segment"
+            }),
+        ))
+        .await;
+        let target = urlencoding::encode(&format!("http://{origin}/slow.ts")).into_owned();
+
+        let request = Request::get(format!("/fetch?url={target}&timeout=1")).body(Body::empty()).unwrap();
+        assert_eq!(proxy(test_state(), request).await.status(), StatusCode::GATEWAY_TIMEOUT);
+
+        for bad in ["0", "-1", "soon"] {
+            let request = Request::get(format!("/fetch?url={target}&timeout={bad}")).body(Body::empty()).unwrap();
+            assert_eq!(proxy(test_state(), request).await.status(), StatusCode::BAD_REQUEST, "{bad}");
+        }
+
+        assert_eq!(parse_timeout("3600"), Some(MAX_REQUEST_TIMEOUT));
+    }
+
+    #[tokio::test]
+    async fn upstream_set_cookie_headers_are_all_passed_through() {
+        let origin = spawn_origin(Router::new().route(
+            "/live.m3u8",
+            get(|| async {
+                (
+                    axum::response::AppendHeaders([
+                        (header::SET_COOKIE, "token=abc; Path=/"),
+                        (header::SET_COOKIE, "session=xyz; HttpOnly"),
+                    ]),
+                    "#EXTM3U\nseg.ts\n",
+                )
+            }),
+        ))
+        .await;
+
+        let request = fetch_request(&format!("http://{origin}/live.m3u8")).body(Body::empty()).unwrap();
+        let res = proxy(test_state(), request).await;
+
+        assert_eq!(res.status(), StatusCode::OK);
+        let cookies: Vec<_> = res.headers().get_all(header::SET_COOKIE).iter().collect();
+        assert_eq!(cookies, ["token=abc; Path=/", "session=xyz; HttpOnly"]);
+    }
+
+    #[tokio::test]
+    async fn client_cookies_are_forwarded_upstream() {
+        let origin = spawn_origin(Router::new().route(
+            "/seg.ts",
+            get(|headers: HeaderMap| async move {
+                headers
+                    .get(header::COOKIE)
+                    .and_then(|v| v.to_str().ok())
+                    .unwrap_or("none")
+                    .to_string()
+            }),
+        ))
+        .await;
+
+        let request = fetch_request(&format!("http://{origin}/seg.ts"))
+            .header(header::COOKIE, "token=abc; session=xyz")
+            .body(Body::empty())
+            .unwrap();
+        let res = proxy(test_state(), request).await;
+
+        assert_eq!(body_text(res).await, "token=abc; session=xyz");
+    }
+
+    #[tokio::test]
+    async fn info_reports_upstream_status_and_headers() {
+        let origin = spawn_origin(Router::new().route(
+            "/gone.ts",
+            get(|| async { (StatusCode::GONE, [("x-edge", "fra1")], "expired") }),
+        ))
+        .await;
+
+        let res = Router::new()
+            .route("/info", get(info_handler))
+            .with_state(test_state())
+            .oneshot(
+                Request::get(format!("/info?url={}", urlencoding::encode(&format!("http://{origin}/gone.ts"))))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        let info: serde_json::Value = serde_json::from_str(&body_text(res).await).unwrap();
+        assert_eq!(info["status"], 410);
+        assert_eq!(info["headers"]["x-edge"], serde_json::json!(["fra1"]));
+    }
+
+    #[test]
+    fn non_utf8_text_bodies_are_decoded() {
+        let latin1 = b"#EXTM3U\n#EXTINF:6,caf\xe9\nseg.ts\n";
+        assert_eq!(
+            decode_text(latin1, "application/vnd.apple.mpegurl; charset=ISO-8859-1"),
+            "#EXTM3U\n#EXTINF:6,caf\u{e9}\nseg.ts\n"
+        );
+        assert_eq!(
+            decode_text(latin1, "application/vnd.apple.mpegurl"),
+            "#EXTM3U\n#EXTINF:6,caf\u{fffd}\nseg.ts\n"
+        );
+        assert_eq!(decode_text("caf\u{e9}".as_bytes(), "text/plain; charset=iso-8859-1"), "caf\u{e9}");
+    }
+
+    #[tokio::test]
+    async fn ready_checks_the_health_url() {
+        async fn ready(health_url: String) -> Response {
+            let state = AppState::new(config::Config {
+                block_private_addresses: false,
+                health_url: Some(url::Url::parse(&health_url).unwrap()),
+                ..Default::default()
+            });
+            Router::new()
+                .route("/ready", get(ready_handler))
+                .with_state(state)
+                .oneshot(Request::get("/ready").body(Body::empty()).unwrap())
+                .await
+                .unwrap()
+        }
+
+        let origin = spawn_origin(Router::new().route("/ping", get(|| async { "pong" }))).await;
+        let res = ready(format!("http://{origin}/ping")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(body_text(res).await.ends_with(" ms"));
+
+        let res = ready("http://127.0.0.1:1/ping".to_string()).await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[tokio::test]
+    async fn user_agent_can_be_configured_and_overridden() {
+        let origin = spawn_origin(Router::new().route(
+            "/ua.ts",
+            get(|headers: HeaderMap| async move { headers[header::USER_AGENT].to_str().unwrap().to_string() }),
+        ))
+        .await;
+        let target = urlencoding::encode(&format!("http://{origin}/ua.ts")).into_owned();
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            user_agent: HeaderValue::from_static("ConfiguredAgent/2.0"),
+            ..Default::default()
+        });
+
+        let res = proxy(state.clone(), fetch_request(&format!("http://{origin}/ua.ts")).body(Body::empty()).unwrap()).await;
+        assert_eq!(body_text(res).await, "ConfiguredAgent/2.0");
+
+        let request = Request::get(format!("/fetch?url={target}&ua=Mobile%20Safari%2F17"))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(body_text(proxy(state.clone(), request).await).await, "Mobile Safari/17");
+
+        let request = Request::get(format!("/fetch?url={target}&ua=bad%0D%0Aagent"))
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(proxy(state, request).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn client_referer_survives_nested_playlists() {
+        let origin = spawn_origin(Router::new()
+            .route("/master.m3u8", get(|| async { "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=1\nmedia.m3u8\n" }))
+            .route(
+                "/media.m3u8",
+                get(|headers: HeaderMap| async move {
+                    let referer = headers[header::REFERER].to_str().unwrap().to_string();
+                    format!("#EXTM3U\n#EXTINF:4,{referer}\nseg.ts\n")
+                }),
+            ))
+            .await;
+
+        let request = Request::get(format!(
+            "/fetch?url={}&ref_={}",
+            urlencoding::encode(&format!("http://{origin}/master.m3u8")),
+            urlencoding::encode("https://player.example.com/watch"),
+        ))
+        .body(Body::empty())
+        .unwrap();
+        let master = body_text(proxy(test_state(), request).await).await;
+        let variant = master.lines().find(|line| line.starts_with("/fetch")).unwrap();
+        assert!(variant.ends_with("&ref_=https%3A%2F%2Fplayer.example.com%2Fwatch"), "{variant}");
+
+        let media = body_text(proxy(test_state(), Request::get(variant).body(Body::empty()).unwrap()).await).await;
+        assert!(media.contains("#EXTINF:4,https://player.example.com/watch"), "{media}");
+        let segment = media.lines().find(|line| line.starts_with("/fetch")).unwrap();
+        assert!(segment.ends_with("&ref_=https%3A%2F%2Fplayer.example.com%2Fwatch"), "{segment}");
+    }
+
+    #[tokio::test]
+    async fn ranges_are_served_from_a_cached_segment() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let origin = spawn_origin(Router::new().route(
+            "/seg.ts",
+            get(move || async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                ([(header::CACHE_CONTROL, "max-age=60")], "0123456789")
+            }),
+        ))
+        .await;
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            cache_bytes: 1 << 20,
+            ..Default::default()
+        });
+        let request = |range: Option<&str>| {
+            let mut builder = fetch_request(&format!("http://{origin}/seg.ts"));
+            if let Some(range) = range {
+                builder = builder.header(header::RANGE, range);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        // a range of an uncached segment goes to the origin and isn't stored
+        proxy(state.clone(), request(Some("bytes=0-1"))).await;
+        assert_eq!(body_text(proxy(state.clone(), request(None)).await).await, "0123456789");
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        let res = proxy(state.clone(), request(Some("bytes=2-5"))).await;
+        assert_eq!(res.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(res.headers()[header::CONTENT_RANGE], "bytes 2-5/10");
+        assert_eq!(res.headers()[header::CONTENT_LENGTH], "4");
+        assert_eq!(body_text(res).await, "2345");
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn missing_or_malformed_query_gets_a_clear_error() {
+        let res = proxy(test_state(), Request::get("/fetch").body(Body::empty()).unwrap()).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(body_text(res).await, "Missing required 'url' query parameter");
+
+        let res = proxy(test_state(), Request::get("/fetch?url=a&url=b").body(Body::empty()).unwrap()).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(body_text(res).await.starts_with("Invalid query string: "));
+    }
+
+    #[tokio::test]
+    async fn configured_headers_are_stripped() {
+        let origin = spawn_origin(Router::new().route(
+            "/live.m3u8",
+            get(|| async { ([(header::SET_COOKIE, "edge=1; Domain=cdn.example.com")], "#EXTM3U\n") }),
+        ))
+        .await;
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            strip_headers: vec![header::SET_COOKIE, header::HeaderName::from_static("cdn-cache-control")],
+            ..Default::default()
+        });
+
+        let res = proxy(state, fetch_request(&format!("http://{origin}/live.m3u8")).body(Body::empty()).unwrap()).await;
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(!res.headers().contains_key(header::SET_COOKIE));
+        assert!(!res.headers().contains_key("cdn-cache-control"));
+        assert!(res.headers().contains_key(header::CACHE_CONTROL));
+    }
+
+    fn headers_for(url: &str, referer: Option<&str>, client_headers: &HeaderMap) -> reqwest_header::HeaderMap {
+        let user_agent = HeaderValue::from_static("TestAgent/1.0");
+        build_upstream_headers(&url::Url::parse(url).unwrap(), referer, user_agent, client_headers, OmittedHeaders::default())
+    }
+
+    #[test]
+    fn upstream_headers_default_to_the_target_origin() {
+        let headers = headers_for("https://cdn.example.com/hls/seg-1.ts?x=1", None, &HeaderMap::new());
+
+        assert_eq!(headers[header::USER_AGENT], "TestAgent/1.0");
+        assert_eq!(headers[header::ACCEPT], "*/*");
+        assert_eq!(headers[header::REFERER], "https://cdn.example.com");
+        assert_eq!(headers[header::ORIGIN], "https://cdn.example.com");
+        // .ts segments always carry a Range
+        assert_eq!(headers[header::RANGE], "bytes=0-");
+    }
+
+    #[test]
+    fn upstream_headers_prefer_client_values() {
+        let mut client = HeaderMap::new();
+        client.insert(header::RANGE, HeaderValue::from_static("bytes=100-199"));
+        client.insert(header::IF_NONE_MATCH, HeaderValue::from_static("\"v1\""));
+        let headers = headers_for("https://cdn.example.com/seg.ts", Some("https://player.example.com/"), &client);
+
+        assert_eq!(headers[header::RANGE], "bytes=100-199");
+        assert_eq!(headers[header::IF_NONE_MATCH], "\"v1\"");
+        assert_eq!(headers[header::REFERER], "https://player.example.com/");
+
+        let playlist = headers_for("https://cdn.example.com/index.m3u8", None, &HeaderMap::new());
+        assert!(!playlist.contains_key(header::RANGE));
+    }
+
+    #[test]
+    fn ip_literal_targets_get_an_origin() {
+        for (url, origin) in [
+            ("http://203.0.113.7:8080/live.m3u8", "http://203.0.113.7:8080"),
+            ("https://203.0.113.7/live.m3u8", "https://203.0.113.7"),
+            ("http://[2001:db8::1]:8443/live.m3u8", "http://[2001:db8::1]:8443"),
+            ("http://[2001:db8::1]/a.m3u8", "http://[2001:db8::1]"),
+            ("https://[2001:db8::1]:443/a.m3u8", "https://[2001:db8::1]"),
+        ] {
+            let headers = headers_for(url, None, &HeaderMap::new());
+            assert_eq!(headers[header::ORIGIN], origin, "{url}");
+            assert_eq!(headers[header::REFERER], origin, "{url}");
+            // well-formed: parses back to the same host and port
+            let parsed = url::Url::parse(headers[header::ORIGIN].to_str().unwrap()).unwrap();
+            assert_eq!(parsed.host(), url::Url::parse(url).unwrap().host(), "{url}");
+            assert_eq!(parsed.port_or_known_default(), url::Url::parse(url).unwrap().port_or_known_default(), "{url}");
+        }
+    }
+
+    #[tokio::test]
+    async fn nesting_depth_is_propagated_and_capped() {
+        let origin = spawn_origin(Router::new().route(
+            "/index.m3u8",
+            get(|headers: HeaderMap| async move {
+                let depth = headers[X_PROXY_DEPTH].to_str().unwrap().to_string();
+                format!("#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH={depth}\nindex.m3u8\n")
+            }),
+        ))
+        .await;
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            max_depth: 2,
+            ..Default::default()
+        });
+
+        let mut link = format!("/fetch?url={}", urlencoding::encode(&format!("http://{origin}/index.m3u8")));
+        for depth in 1..=3 {
+            let text = body_text(proxy(state.clone(), Request::get(&link).body(Body::empty()).unwrap()).await).await;
+            assert!(text.contains(&format!("BANDWIDTH={depth}")), "{text}");
+            link = text.lines().find(|line| line.starts_with("/fetch")).unwrap().to_string();
+            assert!(link.ends_with(&format!("&depth={depth}")), "{link}");
+        }
+        let res = proxy(state.clone(), Request::get(&link).body(Body::empty()).unwrap()).await;
+        assert_eq!(res.status(), StatusCode::LOOP_DETECTED);
+
+        let looped = fetch_request(&format!("http://{origin}/index.m3u8"))
+            .header(X_PROXY_DEPTH, "3")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(proxy(state, looped).await.status(), StatusCode::LOOP_DETECTED);
+    }
+
+    #[tokio::test]
+    async fn authorization_is_forwarded_or_injected() {
+        let origin = spawn_origin(Router::new().route(
+            "/index.m3u8",
+            get(|headers: HeaderMap| async move {
+                let authorization = headers
+                    .get(header::AUTHORIZATION)
+                    .map(|v| v.to_str().unwrap().to_string())
+                    .unwrap_or_default();
+                format!("#EXTM3U\n#EXTINF:4,{authorization}\nseg.ts\n")
+            }),
+        ))
+        .await;
+        let target = format!("http://{origin}/index.m3u8");
+
+        let request = fetch_request(&target)
+            .header(header::AUTHORIZATION, "Bearer from-header")
+            .body(Body::empty())
+            .unwrap();
+        let text = body_text(proxy(test_state(), request).await).await;
+        assert!(text.contains("#EXTINF:4,Bearer from-header"), "{text}");
+
+        let request = Request::get(format!("/fetch?url={}&auth=tok%2B1", urlencoding::encode(&target)))
+            .header(header::AUTHORIZATION, "Bearer from-header")
+            .body(Body::empty())
+            .unwrap();
+        let text = body_text(proxy(test_state(), request).await).await;
+        assert!(text.contains("#EXTINF:4,Bearer tok+1"), "{text}");
+        // segments need the token too when the player can't send it
+        assert!(text.contains("&auth=tok%2B1"), "{text}");
+    }
+
+    #[tokio::test]
+    async fn pin_host_rewrites_segment_hosts() {
+        let origin = spawn_origin(Router::new().route(
+            "/index.m3u8",
+            get(|| async { "#EXTM3U\n#EXTINF:4,\nhttps://cdn2.example.com:8443/v/seg-1.ts?t=1\n" }),
+        ))
+        .await;
+
+        let request = Request::get(format!(
+            "/fetch?url={}&pin_host=cdn7.example.com",
+            urlencoding::encode(&format!("http://{origin}/index.m3u8"))
+        ))
+        .body(Body::empty())
+        .unwrap();
+        let text = body_text(proxy(test_state(), request).await).await;
+        let link = text.lines().find(|line| line.starts_with("/fetch?b64=")).unwrap();
+        let b64 = link.trim_start_matches("/fetch?b64=").split('&').next().unwrap();
+        assert_eq!(links::decode_b64_url(b64).unwrap(), "https://cdn7.example.com:8443/v/seg-1.ts?t=1");
+        assert!(link.ends_with("&pin_host=cdn7.example.com"), "{link}");
+
+        let request = Request::get(format!(
+            "/fetch?url={}&pin_host=cdn7.example.com:80",
+            urlencoding::encode(&format!("http://{origin}/index.m3u8"))
+        ))
+        .body(Body::empty())
+        .unwrap();
+        assert_eq!(proxy(test_state(), request).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn server_timing_reports_upstream_latency() {
+        let origin = spawn_origin(Router::new()
+            .route("/index.m3u8", get(|| async { "#EXTM3U\n" }))
+            .route("/seg.ts", get(|| async { "segment" })))
+            .await;
+
+        for path in ["index.m3u8", "seg.ts"] {
+            let res = proxy(test_state(), fetch_request(&format!("http://{origin}/{path}")).body(Body::empty()).unwrap()).await;
+            let timing = res.headers()["server-timing"].to_str().unwrap();
+            let dur = timing.strip_prefix("upstream;dur=").unwrap_or_else(|| panic!("{path}: {timing}"));
+            assert!(dur.parse::<f64>().is_ok(), "{path}: {timing}");
+        }
+    }
+
+    #[tokio::test]
+    async fn cache_control_can_be_overridden_per_kind() {
+        let origin = spawn_origin(Router::new()
+            .route("/index.m3u8", get(|| async { ([(header::CACHE_CONTROL, "max-age=600")], "#EXTM3U\n") }))
+            .route("/seg.ts", get(|| async { "segment" })))
+            .await;
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            playlist_cache_control: Some("max-age=2".to_string()),
+            segment_cache_control: Some("no-store".to_string()),
+            ..Default::default()
+        });
+
+        for (path, expected) in [("index.m3u8", "max-age=2"), ("seg.ts", "no-store")] {
+            let request = fetch_request(&format!("http://{origin}/{path}")).body(Body::empty()).unwrap();
+            let res = proxy(state.clone(), request).await;
+            assert_eq!(res.headers()[header::CACHE_CONTROL], expected, "{path}");
+            assert_eq!(res.headers()["cdn-cache-control"], expected, "{path}");
+        }
+
+        // unset, the upstream value is kept
+        let request = fetch_request(&format!("http://{origin}/index.m3u8")).body(Body::empty()).unwrap();
+        let res = proxy(test_state(), request).await;
+        assert_eq!(res.headers()[header::CACHE_CONTROL], "max-age=600");
+    }
+
+    #[tokio::test]
+    async fn live_playlists_get_a_short_cache_lifetime() {
+        let origin = spawn_origin(Router::new()
+            .route(
+                "/live.m3u8",
+                get(|| async { ([(header::CACHE_CONTROL, "max-age=3600")], "#EXTM3U\n#EXTINF:6,\nseg-9.ts\n") }),
+            )
+            .route("/vod.m3u8", get(|| async { "#EXTM3U\n#EXTINF:6,\nseg-0.ts\n#EXT-X-ENDLIST\n" })))
+            .await;
+
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            cache_bytes: 1 << 20,
+            ..Default::default()
+        });
+
+        let request = fetch_request(&format!("http://{origin}/live.m3u8")).body(Body::empty()).unwrap();
+        let res = proxy(state.clone(), request).await;
+        assert_eq!(res.headers()[header::CACHE_CONTROL], "max-age=2");
+        assert_eq!(res.headers()["cdn-cache-control"], "max-age=2");
+        // never stored, so not a miss either
+        assert_eq!(res.headers()[X_PROXY_CACHE], "BYPASS");
+
+        let request = fetch_request(&format!("http://{origin}/vod.m3u8")).body(Body::empty()).unwrap();
+        let res = proxy(state, request).await;
+        assert_eq!(res.headers()[header::CACHE_CONTROL], "public, max-age=18000, stale-while-revalidate=300");
+        assert_eq!(res.headers()[X_PROXY_CACHE], "MISS");
+    }
+
+    #[tokio::test]
+    async fn upstream_errors_are_not_cached() {
+        let origin = spawn_origin(Router::new()
+            .route("/gone.ts", get(|| async { (StatusCode::NOT_FOUND, [(header::CACHE_CONTROL, "max-age=86400")], "nope") }))
+            .route("/broken.m3u8", get(|| async { (StatusCode::SERVICE_UNAVAILABLE, "#EXTM3U\n#EXTINF:6,\nseg.ts\n") })))
+            .await;
+
+        for (path, status) in [("gone.ts", StatusCode::NOT_FOUND), ("broken.m3u8", StatusCode::SERVICE_UNAVAILABLE)] {
+            let request = fetch_request(&format!("http://{origin}/{path}")).body(Body::empty()).unwrap();
+            let res = proxy(test_state(), request).await;
+            assert_eq!(res.status(), status, "{path}");
+            assert_eq!(res.headers()[header::CACHE_CONTROL], "no-store", "{path}");
+            assert_eq!(res.headers()["cdn-cache-control"], "no-store", "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn text_responses_are_gzipped_when_accepted() {
+        use std::io::Read;
+
+        let origin = spawn_origin(Router::new()
+            .route("/index.m3u8", get(|| async { "#EXTM3U\n#EXTINF:6,\nseg-0.ts\n#EXT-X-ENDLIST\n" }))
+            .route("/seg.ts", get(|| async { ([(header::CONTENT_TYPE, "video/mp2t")], "segment-bytes") })))
+            .await;
+
+        let request = fetch_request(&format!("http://{origin}/index.m3u8"))
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())
+            .unwrap();
+        let res = proxy(test_state(), request).await;
+        assert_eq!(res.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/vnd.apple.mpegurl");
+        let proxied_bytes: usize = res.headers()[X_PROXY_BYTES].to_str().unwrap().parse().unwrap();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let mut text = String::new();
+        flate2::read::GzDecoder::new(&body[..]).read_to_string(&mut text).unwrap();
+        assert!(text.starts_with("#EXTM3U\n"), "{text}");
+        assert!(text.contains("/fetch?b64="), "{text}");
+        // counted before compression
+        assert_eq!(proxied_bytes, text.len());
+        assert_ne!(proxied_bytes, body.len());
+
+        let request = fetch_request(&format!("http://{origin}/seg.ts"))
+            .header(header::ACCEPT_ENCODING, "gzip")
+            .body(Body::empty())
+            .unwrap();
+        let res = proxy(test_state(), request).await;
+        assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+        assert_eq!(body_text(res).await, "segment-bytes");
+    }
+
+    #[tokio::test]
+    async fn configured_upstream_headers_are_copied() {
+        let origin = spawn_origin(Router::new()
+            .route("/index.m3u8", get(|| async { ([("x-request-id", "abc")], "#EXTM3U\n") }))
+            .route("/seg.ts", get(|| async { ([("x-request-id", "def"), ("x-other", "1")], "segment") })))
+            .await;
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            copy_headers: vec![header::HeaderName::from_static("x-request-id"), header::CONTENT_TYPE],
+            ..Default::default()
+        });
+
+        for (path, id) in [("index.m3u8", "abc"), ("seg.ts", "def")] {
+            let request = fetch_request(&format!("http://{origin}/{path}")).body(Body::empty()).unwrap();
+            let res = proxy(state.clone(), request).await;
+            assert_eq!(res.headers()["x-request-id"], id, "{path}");
+            assert!(!res.headers().contains_key("x-other"), "{path}");
+            assert_eq!(res.headers().get_all(header::CONTENT_TYPE).iter().count(), 1, "{path}");
+        }
+
+        let request = fetch_request(&format!("http://{origin}/seg.ts")).method(Method::HEAD).body(Body::empty()).unwrap();
+        let res = proxy(state, request).await;
+        assert_eq!(res.headers()["x-request-id"], "def");
+    }
+
+    #[tokio::test]
+    async fn truncated_upstream_bodies_abort_the_response() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // promises 100 bytes, sends 10 and hangs up
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let origin = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 1024];
+            let _ = socket.read(&mut request).await;
+            let _ = socket
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-type: video/mp2t\r\ncontent-length: 100\r\n\r\n0123456789")
+                .await;
+        });
+
+        let request = fetch_request(&format!("http://{origin}/seg.ts")).body(Body::empty()).unwrap();
+        let res = proxy(test_state(), request).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(axum::body::to_bytes(res.into_body(), usize::MAX).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn ref_is_accepted_as_an_alias_for_ref_() {
+        let origin = spawn_origin(Router::new().route("/seg.ts", get(|headers: HeaderMap| async move {
+            headers.get(header::REFERER).and_then(|v| v.to_str().ok()).unwrap_or("").to_string()
+        })))
+        .await;
+
+        let url = format!("/fetch?url={}", urlencoding::encode(&format!("http://{origin}/seg.ts")));
+        let request = Request::get(format!("{url}&ref=https%3A%2F%2Fa.example%2F")).body(Body::empty()).unwrap();
+        assert_eq!(body_text(proxy(test_state(), request).await).await, "https://a.example/");
+
+        let both = format!("{url}&ref=https%3A%2F%2Fa.example%2F&ref_=https%3A%2F%2Fb.example%2F");
+        let request = Request::get(both).body(Body::empty()).unwrap();
+        assert_eq!(body_text(proxy(test_state(), request).await).await, "https://b.example/");
+    }
+
+    #[tokio::test]
+    async fn rewrite_only_returns_the_rewritten_playlist_as_text() {
+        let origin = spawn_origin(Router::new()
+            .route("/index.m3u8", get(|| async { "#EXTM3U\n#EXTINF:6,\nseg-0.ts\n#EXT-X-ENDLIST\n" })))
+            .await;
+
+        let target = urlencoding::encode(&format!("http://{origin}/index.m3u8")).into_owned();
+        let request = Request::get(format!("/fetch?url={target}&rewrite_only=1")).body(Body::empty()).unwrap();
+        let res = proxy(test_state(), request).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "text/plain; charset=utf-8");
+        assert_eq!(res.headers()[header::CACHE_CONTROL], "no-store");
+        assert!(res.headers().contains_key("x-proxy-rewrite-only"));
+        let text = body_text(res).await;
+        assert!(text.contains("\n/fetch?b64="), "{text}");
+    }
+
+    #[tokio::test]
+    async fn awkward_segment_names_reach_the_origin_unchanged() {
+        // echoes the path and query exactly as they arrived on the wire
+        let origin = spawn_origin(Router::new()
+            .route("/hls/index.m3u8", get(|| async {
+                "#EXTM3U\n#EXTINF:6,\nseg 1.ts\n#EXTINF:6,\nseg+2.ts\n#EXTINF:6,\nseg%203.ts?sig=a%2Bb%3D&x=1+2\n#EXT-X-ENDLIST\n"
+            }))
+            .fallback(|uri: axum::http::Uri| async move { uri.to_string() }))
+            .await;
+
+        let request = fetch_request(&format!("http://{origin}/hls/index.m3u8")).body(Body::empty()).unwrap();
+        let playlist = body_text(proxy(test_state(), request).await).await;
+        let links: Vec<&str> = playlist.lines().filter(|line| line.starts_with("/fetch?")).collect();
+
+        let expected = ["/hls/seg%201.ts", "/hls/seg+2.ts", "/hls/seg%203.ts?sig=a%2Bb%3D&x=1+2"];
+        assert_eq!(links.len(), expected.len(), "{playlist}");
+        for (link, expected) in links.into_iter().zip(expected) {
+            let res = proxy(test_state(), Request::get(link).body(Body::empty()).unwrap()).await;
+            assert_eq!(body_text(res).await, expected, "{link}");
+        }
+    }
+
+    #[test]
+    fn template_links_decode_back_to_the_target() {
+        for target in [
+            "https://cdn.example.com/dash/seg%201+a.m4s?sig=a%2Bb%3D&x=1+2",
+            "https://cdn.example.com/dash/seg%25.m4s",
+        ] {
+            let link = links::Links::default().template_link(&url::Url::parse(target).unwrap());
+            let query = link.strip_prefix("/fetch?").unwrap();
+            let (_, url) = url::form_urlencoded::parse(query.as_bytes()).find(|(k, _)| k == "url").unwrap();
+            assert_eq!(url, target, "{link}");
+        }
+    }
+
+    #[tokio::test]
+    async fn chunked_upstream_bodies_are_relayed_as_they_arrive() {
+        // the origin only sends its second chunk once the test says so
+        let (tx, rx) = tokio::sync::mpsc::channel::<&'static str>(1);
+        let rx = Arc::new(std::sync::Mutex::new(Some(rx)));
+        let origin = spawn_origin(Router::new().route("/live.ts", get(move || {
+            let rx = rx.lock().unwrap().take().unwrap();
+            async move {
+                let chunks = futures_util::stream::unfold(rx, |mut rx| async move {
+                    rx.recv().await.map(|chunk| (Ok::<_, std::io::Error>(Bytes::from(chunk)), rx))
+                });
+                ([(header::CONTENT_TYPE, "video/mp2t")], Body::from_stream(chunks))
+            }
+        })))
+        .await;
+
+        tx.send("first").await.unwrap();
+        let request = fetch_request(&format!("http://{origin}/live.ts")).body(Body::empty()).unwrap();
+        let res = proxy(test_state(), request).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert!(!res.headers().contains_key(header::CONTENT_LENGTH));
+
+        let mut body = res.into_body().into_data_stream();
+        assert_eq!(body.next().await.unwrap().unwrap(), "first");
+        tx.send("second").await.unwrap();
+        assert_eq!(body.next().await.unwrap().unwrap(), "second");
+        drop(tx);
+        assert!(body.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn buffered_responses_report_the_bytes_proxied() {
+        let origin = spawn_origin(Router::new()
+            .route("/index.m3u8", get(|| async { "#EXTM3U\n#EXTINF:6,\nseg-0.ts\n" }))
+            .route("/seg.ts", get(|| async { ([(header::CACHE_CONTROL, "max-age=60")], "0123456789") })))
+            .await;
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            cache_bytes: 1024 * 1024,
+            ..Default::default()
+        });
+
+        let request = fetch_request(&format!("http://{origin}/index.m3u8")).body(Body::empty()).unwrap();
+        let res = proxy(state.clone(), request).await;
+        let bytes: usize = res.headers()["x-proxy-bytes"].to_str().unwrap().parse().unwrap();
+        assert_eq!(bytes, body_text(res).await.len());
+
+        // buffered for the cache on the first fetch, served from it on the second
+        for _ in 0..2 {
+            let request = fetch_request(&format!("http://{origin}/seg.ts")).body(Body::empty()).unwrap();
+            let res = proxy(state.clone(), request).await;
+            assert_eq!(res.headers()["x-proxy-bytes"], "10");
+        }
+
+        // streamed bodies have no total to report before they're sent
+        let request = fetch_request(&format!("http://{origin}/seg.ts")).body(Body::empty()).unwrap();
+        let res = proxy(test_state(), request).await;
+        assert!(!res.headers().contains_key("x-proxy-bytes"));
+    }
+
+    #[tokio::test]
+    async fn gzipped_playlists_without_content_encoding_are_decompressed() {
+        use flate2::{Compression, write::GzEncoder};
+        use std::io::Write;
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"#EXTM3U\n#EXTINF:6,\nseg-0.ts\n#EXT-X-ENDLIST\n").unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let origin = spawn_origin(Router::new().route("/index.m3u8", get(move || async move {
+            ([(header::CONTENT_TYPE, "application/vnd.apple.mpegurl")], gzipped)
+        })))
+        .await;
+
+        let request = fetch_request(&format!("http://{origin}/index.m3u8")).body(Body::empty()).unwrap();
+        let text = body_text(proxy(test_state(), request).await).await;
+        assert!(text.starts_with("#EXTM3U\n"), "{text}");
+        assert!(text.contains("\n/fetch?b64="), "{text}");
+    }
+
+    #[tokio::test]
+    async fn requests_are_written_to_the_access_log() {
+        let origin = spawn_origin(Router::new()
+            .route("/index.m3u8", get(|| async { "#EXTM3U\n" }))
+            .route("/seg.ts", get(|| async { "0123456789" })))
+            .await;
+        let path = std::env::temp_dir().join(format!("myproxy-access-{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            access_log: Some(path.clone()),
+            ..Default::default()
+        });
+
+        let mut sizes = Vec::new();
+        for name in ["index.m3u8", "seg.ts"] {
+            let request = fetch_request(&format!("http://{origin}/{name}")).body(Body::empty()).unwrap();
+            sizes.push(body_text(proxy(state.clone(), request).await).await.len());
+        }
+        state.flush().await;
+
+        let log = std::fs::read_to_string(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        let entries: Vec<serde_json::Value> = log.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(entries.len(), 2, "{log}");
+        for (entry, bytes) in entries.iter().zip(sizes) {
+            assert_eq!(entry["host"], "127.0.0.1", "{entry}");
+            assert_eq!(entry["path"], "/fetch", "{entry}");
+            assert_eq!(entry["status"], 200, "{entry}");
+            assert_eq!(entry["bytes"], bytes, "{entry}");
+            assert!(entry["ts"].as_f64().is_some() && entry["latency_ms"].as_u64().is_some(), "{entry}");
+        }
+    }
+
+    #[tokio::test]
+    async fn final_url_after_redirects_is_reported() {
+        let origin = spawn_origin(Router::new()
+            .route("/old.ts", get(|| async { axum::response::Redirect::temporary("/shard-2/new.ts") }))
+            .route("/shard-2/new.ts", get(|| async { "segment" })))
+            .await;
+
+        let request = fetch_request(&format!("http://{origin}/old.ts")).body(Body::empty()).unwrap();
+        let res = proxy(test_state(), request).await;
+        assert_eq!(res.headers()["x-proxy-final-url"], format!("http://{origin}/shard-2/new.ts"));
+        assert_eq!(body_text(res).await, "segment");
+    }
+
+    #[tokio::test]
+    async fn playlists_resolve_against_the_redirected_location() {
+        let origin = spawn_origin(Router::new()
+            .route("/live/index.m3u8", get(|| async { axum::response::Redirect::temporary("/edge-3/hls/index.m3u8") }))
+            .route("/edge-3/hls/index.m3u8", get(|| async {
+                (
+                    [(header::CACHE_CONTROL, "max-age=60")],
+                    "#EXTM3U\n#EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\"\n#EXT-X-MAP:URI=\"init.mp4\"\n#EXTINF:6,\nseg-0.ts\n#EXT-X-ENDLIST\n",
+                )
+            })))
+            .await;
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            cache_bytes: 1024 * 1024,
+            ..Default::default()
+        });
+
+        // the second fetch is served from the cache and has to resolve the same way
+        for _ in 0..2 {
+            let request = fetch_request(&format!("http://{origin}/live/index.m3u8")).body(Body::empty()).unwrap();
+            let text = body_text(proxy(state.clone(), request).await).await;
+            let targets: Vec<String> = text
+                .split(['"', '\n'])
+                .filter_map(|part| part.strip_prefix("/fetch?b64="))
+                .map(|query| links::decode_b64_url(query.split('&').next().unwrap()).unwrap())
+                .collect();
+            assert_eq!(
+                targets,
+                ["key.bin", "init.mp4", "seg-0.ts"].map(|name| format!("http://{origin}/edge-3/hls/{name}")),
+                "{text}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn playlists_over_the_line_limit_are_refused() {
+        let origin = spawn_origin(Router::new()
+            .route("/index.m3u8", get(|| async { "#EXTM3U\n#EXTINF:6,\nseg-0.ts\n#EXTINF:6,\nseg-1.ts\n" })))
+            .await;
+
+        for (max_playlist_lines, expected) in [(4, StatusCode::PAYLOAD_TOO_LARGE), (5, StatusCode::OK), (0, StatusCode::OK)] {
+            let state = AppState::new(config::Config {
+                block_private_addresses: false,
+                max_playlist_lines,
+                ..Default::default()
+            });
+            let request = fetch_request(&format!("http://{origin}/index.m3u8")).body(Body::empty()).unwrap();
+            assert_eq!(proxy(state, request).await.status(), expected, "{max_playlist_lines}");
+        }
+    }
+
+    #[tokio::test]
+    async fn disallowed_content_types_are_refused() {
+        let origin = spawn_origin(Router::new()
+            .route("/seg.ts", get(|| async { ([(header::CONTENT_TYPE, "Video/MP2T")], "segment") }))
+            .route("/index.m3u8", get(|| async { ([(header::CONTENT_TYPE, "application/vnd.apple.mpegurl")], "#EXTM3U\n") }))
+            .route("/login.html", get(|| async { axum::response::Html("<form>") })))
+            .await;
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            allowed_content_types: Some(vec!["video/".to_string(), "application/vnd.apple.mpegurl".to_string()]),
+            ..Default::default()
+        });
+
+        for (path, expected) in [
+            ("seg.ts", StatusCode::OK),
+            ("index.m3u8", StatusCode::OK),
+            ("login.html", StatusCode::UNSUPPORTED_MEDIA_TYPE),
+        ] {
+            let request = fetch_request(&format!("http://{origin}/{path}")).body(Body::empty()).unwrap();
+            assert_eq!(proxy(state.clone(), request).await.status(), expected, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn short_rate_limits_are_waited_out_once() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let origin = spawn_origin(Router::new()
+            .route("/once.m3u8", get(move || async move {
+                if counter.fetch_add(1, Ordering::SeqCst) == 0 {
+                    (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, "0")], "slow down")
+                } else {
+                    (StatusCode::OK, [(header::RETRY_AFTER, "0")], "#EXTM3U\n")
+                }
+            }))
+            .route("/always.m3u8", get(|| async { (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, "0")], "slow down") }))
+            .route("/long.m3u8", get(|| async { (StatusCode::TOO_MANY_REQUESTS, [(header::RETRY_AFTER, "30")], "slow down") })))
+            .await;
+
+        let request = fetch_request(&format!("http://{origin}/once.m3u8")).body(Body::empty()).unwrap();
+        assert_eq!(proxy(test_state(), request).await.status(), StatusCode::OK);
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        for path in ["always.m3u8", "long.m3u8"] {
+            let request = fetch_request(&format!("http://{origin}/{path}")).body(Body::empty()).unwrap();
+            let started = Instant::now();
+            assert_eq!(proxy(test_state(), request).await.status(), StatusCode::TOO_MANY_REQUESTS, "{path}");
+            assert!(started.elapsed() < Duration::from_secs(5), "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn redirects_can_be_left_to_the_client() {
+        let origin = spawn_origin(Router::new()
+            .route("/old.ts", get(|| async { axum::response::Redirect::temporary("/new.ts") }))
+            .route("/new.ts", get(|| async { "segment" })))
+            .await;
+
+        let target = urlencoding::encode(&format!("http://{origin}/old.ts")).into_owned();
+        let request = Request::get(format!("/fetch?url={target}&follow_redirects=0")).body(Body::empty()).unwrap();
+        let res = proxy(test_state(), request).await;
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+        let location = res.headers()[header::LOCATION].to_str().unwrap().to_string();
+        let b64 = location.strip_prefix("/fetch?b64=").unwrap().split('&').next().unwrap();
+        assert_eq!(links::decode_b64_url(b64).unwrap(), format!("http://{origin}/new.ts"));
+        assert!(location.ends_with("&follow_redirects=0"), "{location}");
+
+        let res = proxy(test_state(), Request::get(location).body(Body::empty()).unwrap()).await;
+        assert_eq!(body_text(res).await, "segment");
+
+        // followed as usual otherwise
+        let request = fetch_request(&format!("http://{origin}/old.ts")).body(Body::empty()).unwrap();
+        assert_eq!(body_text(proxy(test_state(), request).await).await, "segment");
+    }
+
+    #[tokio::test]
+    async fn start_offset_is_injected_into_media_playlists() {
+        let origin = spawn_origin(Router::new()
+            .route("/media.m3u8", get(|| async { "#EXTM3U\n#EXT-X-START:TIME-OFFSET=5\n#EXTINF:6,\nseg-0.ts\n#EXT-X-ENDLIST\n" }))
+            .route("/master.m3u8", get(|| async { "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=1\nlow.m3u8\n" })))
+            .await;
+        let fetch_with_start = |path: &str, start: &str| {
+            let target = urlencoding::encode(&format!("http://{origin}/{path}")).into_owned();
+            Request::get(format!("/fetch?url={target}&start={start}")).body(Body::empty()).unwrap()
+        };
+
+        let text = body_text(proxy(test_state(), fetch_with_start("media.m3u8", "12.5")).await).await;
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[..2], ["#EXTM3U", "#EXT-X-START:TIME-OFFSET=12.5"], "{text}");
+        assert_eq!(text.matches("#EXT-X-START").count(), 1, "{text}");
+
+        let text = body_text(proxy(test_state(), fetch_with_start("master.m3u8", "30")).await).await;
+        assert!(!text.contains("#EXT-X-START"), "{text}");
+
+        for start in ["-1", "soon", "inf"] {
+            let res = proxy(test_state(), fetch_with_start("media.m3u8", start)).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{start}");
+        }
+    }
+
+    #[tokio::test]
+    async fn rewritten_playlists_can_be_revalidated() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let origin = spawn_origin(Router::new().route("/vod.m3u8", get(move || async move {
+            counter.fetch_add(1, Ordering::SeqCst);
+            ([(header::ETAG, "\"upstream\"")], "#EXTM3U\n#EXTINF:6,\nseg-0.ts\n#EXT-X-ENDLIST\n")
+        })))
+        .await;
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            cache_bytes: 1024 * 1024,
+            ..Default::default()
+        });
+
+        let request = fetch_request(&format!("http://{origin}/vod.m3u8")).body(Body::empty()).unwrap();
+        let res = proxy(state.clone(), request).await;
+        let etag = res.headers()[header::ETAG].clone();
+        assert_ne!(etag, "\"upstream\"");
+
+        for tag in [etag.to_str().unwrap().to_string(), format!("\"other\", W/{}", etag.to_str().unwrap())] {
+            let request = fetch_request(&format!("http://{origin}/vod.m3u8"))
+                .header(header::IF_NONE_MATCH, tag.as_str())
+                .body(Body::empty())
+                .unwrap();
+            let res = proxy(state.clone(), request).await;
+            assert_eq!(res.status(), StatusCode::NOT_MODIFIED, "{tag}");
+            assert_eq!(res.headers()[header::ETAG], etag);
+            assert!(body_text(res).await.is_empty());
+        }
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        let request = fetch_request(&format!("http://{origin}/vod.m3u8"))
+            .header(header::IF_NONE_MATCH, "\"stale\"")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(proxy(state, request).await.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn oversized_upstream_headers_are_rejected() {
+        let origin = spawn_origin(Router::new()
+            .route("/many.ts", get(|| async {
+                let mut headers = HeaderMap::new();
+                for i in 0..10 {
+                    headers.insert(header::HeaderName::try_from(format!("x-h-{i}")).unwrap(), HeaderValue::from_static("1"));
+                }
+                (headers, "segment")
+            }))
+            .route("/big.ts", get(|| async { ([("x-big", "a".repeat(500))], "segment") }))
+            .route("/ok.ts", get(|| async { "segment" })))
+            .await;
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            max_upstream_headers: 8,
+            max_upstream_header_bytes: 400,
+            ..Default::default()
+        });
+
+        for (path, expected) in [("many.ts", StatusCode::BAD_GATEWAY), ("big.ts", StatusCode::BAD_GATEWAY), ("ok.ts", StatusCode::OK)] {
+            let request = fetch_request(&format!("http://{origin}/{path}")).body(Body::empty()).unwrap();
+            assert_eq!(proxy(state.clone(), request).await.status(), expected, "{path}");
+        }
+    }
+
+    #[tokio::test]
+    async fn identical_concurrent_fetches_share_one_upstream_request() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let origin = spawn_origin(Router::new()
+            .route("/seg.ts", get(move || async move {
+                counter.fetch_add(1, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                "segment"
+            })))
+            .await;
+        let state = test_state();
+
+        let fetches = (0..5).map(|_| {
+            let request = fetch_request(&format!("http://{origin}/seg.ts")).body(Body::empty()).unwrap();
+            let state = state.clone();
+            async move { body_text(proxy(state, request).await).await }
+        });
+        let bodies = futures_util::future::join_all(fetches).await;
+        assert!(bodies.iter().all(|body| body == "segment"), "{bodies:?}");
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        // different cookies mean different requests upstream
+        let fetches = ["a=1", "a=2"].map(|cookie| {
+            let request = fetch_request(&format!("http://{origin}/seg.ts"))
+                .header(header::COOKIE, cookie)
+                .body(Body::empty())
+                .unwrap();
+            proxy(state.clone(), request)
+        });
+        futures_util::future::join_all(fetches).await;
+        assert_eq!(hits.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn cache_status_is_reported() {
+        let origin = spawn_origin(Router::new()
+            .route("/seg.ts", get(|| async { ([(header::CACHE_CONTROL, "max-age=60")], "0123456789") }))
+            .route("/private.ts", get(|| async { ([(header::CACHE_CONTROL, "no-store")], "0123456789") })))
+            .await;
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            cache_bytes: 1024 * 1024,
+            ..Default::default()
+        });
+        let seg = format!("http://{origin}/seg.ts");
+
+        let res = proxy(state.clone(), fetch_request(&seg).body(Body::empty()).unwrap()).await;
+        assert_eq!(res.headers()["x-proxy-cache"], "MISS");
+        assert!(!res.headers().contains_key(header::AGE));
+
+        let res = proxy(state.clone(), fetch_request(&seg).body(Body::empty()).unwrap()).await;
+        assert_eq!(res.headers()["x-proxy-cache"], "HIT");
+        assert_eq!(res.headers()[header::AGE], "0");
+
+        // ranges are cut from a complete cached body
+        let request = fetch_request(&seg).header(header::RANGE, "bytes=0-3").body(Body::empty()).unwrap();
+        let res = proxy(state.clone(), request).await;
+        assert_eq!(res.headers()["x-proxy-cache"], "HIT");
+
+        let request = fetch_request(&seg).method(Method::HEAD).body(Body::empty()).unwrap();
+        let res = proxy(state.clone(), request).await;
+        assert_eq!(res.headers()["x-proxy-cache"], "BYPASS");
+
+        let private = format!("http://{origin}/private.ts");
+        let res = proxy(state.clone(), fetch_request(&private).body(Body::empty()).unwrap()).await;
+        assert_eq!(res.headers()["x-proxy-cache"], "BYPASS");
+
+        let res = proxy(test_state(), fetch_request(&seg).body(Body::empty()).unwrap()).await;
+        assert_eq!(res.headers()["x-proxy-cache"], "BYPASS");
+    }
+
+    #[tokio::test]
+    async fn referer_and_origin_can_be_left_out() {
+        let origin = spawn_origin(Router::new()
+            .route("/index.m3u8", get(|| async { "#EXTM3U\n#EXTINF:6,\nseg.ts\n" }))
+            .route("/seg.ts", get(|headers: HeaderMap| async move {
+                format!("{} {}", headers.contains_key(header::REFERER), headers.contains_key(header::ORIGIN))
+            })))
+            .await;
+        let target = urlencoding::encode(&format!("http://{origin}/seg.ts")).into_owned();
+        let sent = |state: AppState, query: &str| {
+            let request = Request::get(format!("/fetch?url={target}{query}")).body(Body::empty()).unwrap();
+            async move { body_text(proxy(state, request).await).await }
+        };
+
+        assert_eq!(sent(test_state(), "").await, "true true");
+        assert_eq!(sent(test_state(), "&no_referer=1").await, "false true");
+        assert_eq!(sent(test_state(), "&no_origin=1").await, "true false");
+
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            no_referer: true,
+            no_origin: true,
+            ..Default::default()
+        });
+        assert_eq!(sent(state.clone(), "").await, "false false");
+        assert_eq!(sent(state, "&no_referer=0").await, "true false");
+
+        // segments of a playlist are fetched the same way
+        let playlist = urlencoding::encode(&format!("http://{origin}/index.m3u8")).into_owned();
+        let request = Request::get(format!("/fetch?url={playlist}&no_referer=1")).body(Body::empty()).unwrap();
+        let text = body_text(proxy(test_state(), request).await).await;
+        let link = text.lines().find(|line| line.starts_with("/fetch?")).unwrap();
+        assert!(link.ends_with("&no_referer=1"), "{link}");
+        let res = proxy(test_state(), Request::get(link).body(Body::empty()).unwrap()).await;
+        assert_eq!(body_text(res).await, "false true");
+    }
+
+    #[test]
+    fn ipv6_literal_upstreams_keep_their_brackets() {
+        let headers = headers_for("http://[::1]:8080/a.ts", None, &HeaderMap::new());
+        assert_eq!(headers[header::ORIGIN], "http://[::1]:8080");
+        assert_eq!(headers[header::REFERER], "http://[::1]:8080");
+    }
+
+    #[tokio::test]
+    async fn ipv6_literal_upstreams_are_fetched() {
+        let Ok(listener) = tokio::net::TcpListener::bind("[::1]:0").await else {
+            eprintln!("skipping ipv6_literal_upstreams_are_fetched: no IPv6 loopback to bind");
+            return;
+        };
+        let port = listener.local_addr().unwrap().port();
+        let origin = Router::new().route("/a.ts", get(|headers: HeaderMap| async move {
+            headers[header::ORIGIN].to_str().unwrap().to_string()
+        }));
+        tokio::spawn(async move { axum::serve(listener, origin).await.unwrap() });
+
+        let request = fetch_request(&format!("http://[::1]:{port}/a.ts")).body(Body::empty()).unwrap();
+        let res = proxy(test_state(), request).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body_text(res).await, format!("http://[::1]:{port}"));
+    }
+
+    #[tokio::test]
+    async fn overlong_urls_are_refused() {
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            max_url_len: 64,
+            ..Default::default()
+        });
+        let target = format!("http://origin.invalid/{}", "a".repeat(64));
+
+        let res = proxy(state.clone(), fetch_request(&target).body(Body::empty()).unwrap()).await;
+        assert_eq!(res.status(), StatusCode::URI_TOO_LONG);
+
+        // b64 values are measured decoded
+        use base64::Engine;
+        let b64 = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(&target);
+        let res = proxy(state.clone(), Request::get(format!("/fetch?b64={b64}")).body(Body::empty()).unwrap()).await;
+        assert_eq!(res.status(), StatusCode::URI_TOO_LONG);
+        let res = proxy(state, fetch_request("http://127.0.0.1:1/short.ts").body(Body::empty()).unwrap()).await;
+        assert_ne!(res.status(), StatusCode::URI_TOO_LONG);
+    }
+
+    #[tokio::test]
+    async fn prefetch_warms_the_cache_with_the_last_segments() {
+        let hits = Arc::new(std::sync::Mutex::new(Vec::<String>::new()));
+        let seen = hits.clone();
+        let origin = spawn_origin(Router::new()
+            .route("/live.m3u8", get(|| async {
+                "#EXTM3U\n#EXTINF:6,\nseg-1.ts\n#EXTINF:6,\nseg-2.ts\n#EXTINF:6,\nseg-3.ts\n"
+            }))
+            .route("/master.m3u8", get(|| async { "#EXTM3U\n#EXT-X-STREAM-INF:BANDWIDTH=1\nlive.m3u8\n" }))
+            .route("/{name}", get(move |axum::extract::Path(name): axum::extract::Path<String>| async move {
+                seen.lock().unwrap().push(name.clone());
+                // one without a Content-Length, streamed by the origin
+                let body = match name.as_str() {
+                    "seg-3.ts" => Body::from_stream(futures_util::stream::iter([Ok::<_, std::io::Error>(Bytes::from(name))])),
+                    _ => Body::from(name),
+                };
+                ([(header::CACHE_CONTROL, "max-age=60")], body)
+            })))
+            .await;
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            cache_bytes: 1024 * 1024,
+            ..Default::default()
+        });
+        let playlist = urlencoding::encode(&format!("http://{origin}/live.m3u8")).into_owned();
+
+        let request = Request::get(format!("/prefetch?url={playlist}&n=2")).body(Body::empty()).unwrap();
+        let res = proxy(state.clone(), request).await;
+        assert_eq!(res.status(), StatusCode::ACCEPTED);
+        assert_eq!(body_text(res).await, r#"{"queued":2}"#);
+
+        // every permit back means every body has been read into the cache
+        for _ in 0..200 {
+            if state.prefetches.available_permits() == PREFETCH_TASKS {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let mut fetched = hits.lock().unwrap().clone();
+        fetched.sort();
+        assert_eq!(fetched, ["seg-2.ts", "seg-3.ts"]);
+
+        for name in ["seg-2.ts", "seg-3.ts"] {
+            let res = proxy(state.clone(), fetch_request(&format!("http://{origin}/{name}")).body(Body::empty()).unwrap()).await;
+            assert_eq!(res.headers()["x-proxy-cache"], "HIT", "{name}");
+            assert_eq!(body_text(res).await, name);
+        }
+
+        let master = urlencoding::encode(&format!("http://{origin}/master.m3u8")).into_owned();
+        let request = Request::get(format!("/prefetch?url={master}")).body(Body::empty()).unwrap();
+        assert_eq!(proxy(state.clone(), request).await.status(), StatusCode::BAD_REQUEST);
+
+        // nor for credentials, their responses stay out of the cache
+        for request in [
+            Request::get(format!("/prefetch?url={playlist}")).header(header::COOKIE, "session=1").body(Body::empty()).unwrap(),
+            Request::get(format!("/prefetch?url={playlist}&auth=tok")).body(Body::empty()).unwrap(),
+        ] {
+            let res = proxy(state.clone(), request).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+            assert!(body_text(res).await.contains("credentials"));
+        }
+
+        // nothing to warm without a cache
+        let request = Request::get(format!("/prefetch?url={playlist}")).body(Body::empty()).unwrap();
+        assert_eq!(proxy(test_state(), request).await.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn stale_entries_stand_in_for_a_failing_upstream() {
+        let failing = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let flag = failing.clone();
+        let origin = spawn_origin(Router::new().route("/seg.ts", get(move || {
+            let failing = flag.load(std::sync::atomic::Ordering::SeqCst);
+            async move {
+                match failing {
+                    true => (StatusCode::BAD_GATEWAY, [(header::CACHE_CONTROL, "no-store")], "down"),
+                    false => (StatusCode::OK, [(header::CACHE_CONTROL, "max-age=1")], "segment"),
+                }
+            }
+        })))
+        .await;
+        let seg = format!("http://{origin}/seg.ts");
+        let cached_state = |stale_if_error| AppState::new(config::Config {
+            block_private_addresses: false,
+            cache_bytes: 1024 * 1024,
+            retries: 0,
+            stale_if_error,
+            ..Default::default()
+        });
+        let (state, strict) = (cached_state(Duration::from_secs(30)), cached_state(Duration::ZERO));
+        for state in [&state, &strict] {
+            let res = proxy(state.clone(), fetch_request(&seg).body(Body::empty()).unwrap()).await;
+            assert_eq!(body_text(res).await, "segment");
+        }
+
+        failing.store(true, std::sync::atomic::Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+
+        let res = proxy(state.clone(), fetch_request(&seg).body(Body::empty()).unwrap()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["x-proxy-cache"], "STALE");
+        assert_eq!(res.headers()[header::WARNING], "111 - \"Revalidation Failed\"");
+        assert_eq!(res.headers()[header::AGE], "1");
+        assert_eq!(res.headers()["server-timing"], "cache;desc=\"stale\"");
+        assert_eq!(body_text(res).await, "segment");
+
+        let res = proxy(strict, fetch_request(&seg).body(Body::empty()).unwrap()).await;
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn mislabelled_playlists_are_sniffed() {
+        let origin = spawn_origin(Router::new()
+            .route("/playlist", get(|| async {
+                ([(header::CONTENT_TYPE, "application/octet-stream")], "#EXTM3U\n#EXTINF:6,\nseg-0.ts\n")
+            }))
+            .route("/blob", get(|| async { ([(header::CONTENT_TYPE, "application/octet-stream")], "\x47\x40\x00") })))
+            .await;
+
+        let res = proxy(test_state(), fetch_request(&format!("http://{origin}/playlist")).body(Body::empty()).unwrap()).await;
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/vnd.apple.mpegurl");
+        let text = body_text(res).await;
+        assert!(text.contains("\n/fetch?b64="), "{text}");
+
+        let res = proxy(test_state(), fetch_request(&format!("http://{origin}/blob")).body(Body::empty()).unwrap()).await;
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/octet-stream");
+        assert_eq!(body_text(res).await, "\x47\x40\x00");
+    }
+
+    #[tokio::test]
+    async fn mislabelled_playlists_without_a_length_are_sniffed() {
+        // streamed bodies go out chunked, without a Content-Length
+        fn chunked(chunks: Vec<Vec<u8>>) -> ([(header::HeaderName, &'static str); 1], Body) {
+            let chunks = futures_util::stream::iter(chunks.into_iter().map(|chunk| Ok::<_, std::io::Error>(Bytes::from(chunk))));
+            ([(header::CONTENT_TYPE, "application/octet-stream")], Body::from_stream(chunks))
+        }
+        let origin = spawn_origin(Router::new()
+            .route("/playlist", get(|| async {
+                let entries = "#EXTINF:6,\nseg-0.ts\n".repeat(64);
+                chunked(vec![b"#EXT".to_vec(), b"M3U\n".to_vec(), entries.into_bytes()])
+            }))
+            .route("/blob", get(|| async { chunked(vec![vec![0x47; 300], vec![0x40; 300], vec![0x00; 300]]) })))
+            .await;
+
+        let res = proxy(test_state(), fetch_request(&format!("http://{origin}/playlist")).body(Body::empty()).unwrap()).await;
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/vnd.apple.mpegurl");
+        let text = body_text(res).await;
+        assert_eq!(text.matches("\n/fetch?b64=").count(), 64, "{text}");
+
+        // whatever was read to look is sent ahead of the rest
+        let res = proxy(test_state(), fetch_request(&format!("http://{origin}/blob")).body(Body::empty()).unwrap()).await;
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/octet-stream");
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, [vec![0x47; 300], vec![0x40; 300], vec![0x00; 300]].concat());
+    }
+
+    #[tokio::test]
+    async fn streamed_bodies_are_counted_until_done() {
+        let origin = spawn_origin(Router::new().route("/live.mp4", get(|| async {
+            let chunks = futures_util::stream::iter(
+                (0..4).map(|_| Ok::<_, std::io::Error>(Bytes::from(vec![0u8; 1024]))),
+            );
+            Body::from_stream(chunks)
+        })))
+        .await;
+        let state = test_state();
+
+        let res = proxy(state.clone(), fetch_request(&format!("http://{origin}/live.mp4")).body(Body::empty()).unwrap()).await;
+        assert_eq!(state.open_streams(), 1);
+        assert_eq!(axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap().len(), 4096);
+        assert_eq!(state.open_streams(), 0);
+    }
+
+    #[tokio::test]
+    async fn content_type_can_be_forced() {
+        let origin = spawn_origin(Router::new().route("/seg", get(|| async {
+            ([(header::CONTENT_TYPE, "application/octet-stream")], "\x47\x40\x00")
+        })))
+        .await;
+        let target = urlencoding::encode(&format!("http://{origin}/seg")).into_owned();
+
+        let request = Request::get(format!("/fetch?url={target}&ct=video%2Fmp2t")).body(Body::empty()).unwrap();
+        let res = proxy(test_state(), request).await;
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "video/mp2t");
+
+        let request = Request::get(format!("/fetch?url={target}&ct=video%2Fmp2t")).method(Method::HEAD).body(Body::empty()).unwrap();
+        let res = proxy(test_state(), request).await;
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "video/mp2t");
+
+        for ct in ["", "video%2Fmp2t%0D%0AX-Evil%3A%201"] {
+            let request = Request::get(format!("/fetch?url={target}&ct={ct}")).body(Body::empty()).unwrap();
+            assert_eq!(proxy(test_state(), request).await.status(), StatusCode::BAD_REQUEST, "{ct}");
+        }
+    }
+
+    #[tokio::test]
+    async fn credentialed_responses_are_not_shared_through_the_cache() {
+        let origin = spawn_origin(Router::new()
+            .route("/seg.ts", get(|headers: HeaderMap| async move {
+                match headers.get(header::AUTHORIZATION) {
+                    Some(_) => ([(header::CACHE_CONTROL, "public, max-age=60")], "secret-bytes"),
+                    None => ([(header::CACHE_CONTROL, "public, max-age=60")], "anonymous"),
+                }
+            }))
+            .route("/index.m3u8", get(|| async { "#EXTM3U\n#EXTINF:6,\nseg.ts\n#EXT-X-ENDLIST\n" })))
+            .await;
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            cache_bytes: 1024 * 1024,
+            ..Default::default()
+        });
+        let seg = format!("http://{origin}/seg.ts");
+
+        let request = fetch_request(&seg).header(header::AUTHORIZATION, "Bearer t0ken").body(Body::empty()).unwrap();
+        let res = proxy(state.clone(), request).await;
+        assert_eq!(res.headers()["x-proxy-cache"], "BYPASS");
+        assert_eq!(res.headers()[header::CACHE_CONTROL], "private, max-age=60");
+        assert_eq!(res.headers()["cdn-cache-control"], "no-store");
+        assert_eq!(body_text(res).await, "secret-bytes");
+
+        let target = urlencoding::encode(&seg).into_owned();
+        let request = Request::get(format!("/fetch?url={target}&auth=t0ken")).body(Body::empty()).unwrap();
+        assert_eq!(body_text(proxy(state.clone(), request).await).await, "secret-bytes");
+
+        let res = proxy(state.clone(), fetch_request(&seg).body(Body::empty()).unwrap()).await;
+        assert_eq!(res.headers()["x-proxy-cache"], "MISS");
+        assert_eq!(body_text(res).await, "anonymous");
+
+        // playlists linking with `&auth=` are private too
+        let playlist = urlencoding::encode(&format!("http://{origin}/index.m3u8")).into_owned();
+        let request = Request::get(format!("/fetch?url={playlist}&auth=t0ken")).body(Body::empty()).unwrap();
+        let res = proxy(state, request).await;
+        assert_eq!(res.headers()[header::CACHE_CONTROL], "private, max-age=18000, stale-while-revalidate=300");
+        assert!(body_text(res).await.contains("&auth=t0ken"));
+    }
+
+    #[tokio::test]
+    async fn responses_to_forwarded_cookies_are_not_cached() {
+        let origin = spawn_origin(Router::new().route("/seg.ts", get(|headers: HeaderMap| async move {
+            let body = match headers.get(header::COOKIE) {
+                Some(cookie) => cookie.to_str().unwrap().to_string(),
+                None => "anonymous".to_string(),
+            };
+            ([(header::CACHE_CONTROL, "max-age=60")], body)
+        })))
+        .await;
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            cache_bytes: 1024 * 1024,
+            ..Default::default()
+        });
+        let seg = format!("http://{origin}/seg.ts");
+
+        let res = proxy(state.clone(), fetch_request(&seg).body(Body::empty()).unwrap()).await;
+        assert_eq!(body_text(res).await, "anonymous");
+
+        // a cookie neither gets the cached anonymous body nor replaces it
+        let request = fetch_request(&seg).header(header::COOKIE, "session=abc").body(Body::empty()).unwrap();
+        let res = proxy(state.clone(), request).await;
+        assert_eq!(res.headers()["x-proxy-cache"], "BYPASS");
+        assert!(res.headers()[header::CACHE_CONTROL].to_str().unwrap().starts_with("private"));
+        assert_eq!(body_text(res).await, "session=abc");
+
+        let res = proxy(state, fetch_request(&seg).body(Body::empty()).unwrap()).await;
+        assert_eq!(res.headers()["x-proxy-cache"], "HIT");
+        assert_eq!(body_text(res).await, "anonymous");
+    }
+
+    #[tokio::test]
+    async fn ranges_of_cached_playlists_get_the_whole_rewritten_body() {
+        let origin = spawn_origin(Router::new().route("/live.m3u8", get(|| async {
+            ([(header::CACHE_CONTROL, "max-age=60")], "#EXTM3U\n#EXTINF:6,\nseg-0.ts\n#EXTINF:6,\nseg-1.ts\n#EXT-X-ENDLIST\n")
+        })))
+        .await;
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            cache_bytes: 1024 * 1024,
+            ..Default::default()
+        });
+        let playlist = format!("http://{origin}/live.m3u8");
+
+        let full = body_text(proxy(state.clone(), fetch_request(&playlist).body(Body::empty()).unwrap()).await).await;
+        let request = fetch_request(&playlist).header(header::RANGE, "bytes=0-20").body(Body::empty()).unwrap();
+        let res = proxy(state, request).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["x-proxy-cache"], "HIT");
+        assert!(!res.headers().contains_key(header::CONTENT_RANGE));
+        assert_eq!(body_text(res).await, full);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_and_bad_prefetch_requests_get_json_errors() {
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            cache_bytes: 1024 * 1024,
+            rate_limit: Some(0.001),
+            rate_burst: Some(1.0),
+            ..Default::default()
+        });
+        let client = axum::extract::ConnectInfo(SocketAddr::from(([203, 0, 113, 7], 40000)));
+        let request = |uri: &str| {
+            Request::get(uri)
+                .header(header::ACCEPT, "application/json")
+                .extension(client)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let res = proxy(state.clone(), request("/prefetch?url=http%3A%2F%2Forigin.invalid%2Fa.m3u8&n=many")).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        assert!(body_text(res).await.contains(r#""code":"INVALID_QUERY""#));
+
+        // the limiter lives in the router, so both requests go through one
+        let app = build_app(state);
+        let _ = app.clone().oneshot(request("/fetch?url=http%3A%2F%2F127.0.0.1%3A1%2Fa.ts")).await.unwrap();
+        let res = app.oneshot(request("/fetch?url=http%3A%2F%2F127.0.0.1%3A1%2Fa.ts")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(res.headers().contains_key(header::RETRY_AFTER));
+        assert_eq!(res.headers()[header::CONTENT_TYPE], "application/json");
+        assert!(body_text(res).await.contains(r#""code":"RATE_LIMITED""#));
+    }
+
+    #[tokio::test]
+    async fn prefetch_is_rate_limited_with_fetch() {
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            cache_bytes: 1024 * 1024,
+            rate_limit: Some(0.001),
+            rate_burst: Some(1.0),
+            ..Default::default()
+        });
+        let client = axum::extract::ConnectInfo(SocketAddr::from(([203, 0, 113, 7], 40000)));
+        let request = |uri: &str| Request::get(uri).extension(client).body(Body::empty()).unwrap();
+
+        let app = build_app(state);
+        let _ = app.clone().oneshot(request("/fetch?url=http%3A%2F%2F127.0.0.1%3A1%2Fa.ts")).await.unwrap();
+        let res = app.oneshot(request("/prefetch?url=http%3A%2F%2F127.0.0.1%3A1%2Fa.m3u8")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[tokio::test]
+    async fn signed_segment_template_links_work_once_filled_in() {
+        let origin = spawn_origin(Router::new()
+            .route("/dash/stream.mpd", get(|| async {
+                (
+                    [(header::CONTENT_TYPE, "application/dash+xml")],
+                    r#"<MPD><Period><AdaptationSet><SegmentTemplate media="$RepresentationID$/seg-$Number%05d$.m4s"/><Representation id="720p"/></AdaptationSet></Period></MPD>"#,
+                )
+            }))
+            .route("/dash/720p/seg-00001.m4s", get(|| async { "segment" })))
+            .await;
+        let key = b"secret".to_vec();
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            signing_key: Some(key.clone()),
+            ..Default::default()
+        });
+
+        let target = format!("http://{origin}/dash/stream.mpd");
+        let uri = format!("/fetch?url={}&sig={}", urlencoding::encode(&target), links::sign_url(&key, &target));
+        let manifest = body_text(proxy(state.clone(), Request::get(uri).body(Body::empty()).unwrap()).await).await;
+        let start = manifest.find("media=\"").unwrap() + 7;
+        let media = manifest[start..start + manifest[start..].find('"').unwrap()].replace("&amp;", "&");
+        assert!(media.contains("&tpl="), "{media}");
+
+        // what the player requests once it has substituted the identifiers
+        let segment = media.replace("$RepresentationID$", "720p").replace("$Number%05d$", "00001");
+        let res = proxy(state.clone(), Request::get(segment).body(Body::empty()).unwrap()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(body_text(res).await, "segment");
+
+        // the template only stands for digits and single path segments
+        for filled in [media.replace("$RepresentationID$", "720p").replace("$Number%05d$", "x"),
+                       media.replace("$RepresentationID$", "..%2F..%2Fadmin").replace("$Number%05d$", "1")] {
+            let res = proxy(state.clone(), Request::get(filled.as_str()).body(Body::empty()).unwrap()).await;
+            assert_eq!(res.status(), StatusCode::FORBIDDEN, "{filled}");
+        }
+    }
+
+    #[tokio::test]
+    async fn private_upstreams_are_refused_unless_allowed() {
+        let origin = spawn_origin(Router::new().route("/seg.ts", get(|| async { "segment" }))).await;
+        let target = format!("http://{origin}/seg.ts");
+
+        // the default, PROXY_ALLOW_PRIVATE unset
+        let res = proxy(AppState::new(config::Config::default()), fetch_request(&target).body(Body::empty()).unwrap()).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert!(body_text(res).await.contains("Forbidden target"));
+
+        let res = proxy(test_state(), fetch_request(&target).body(Body::empty()).unwrap()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn redirects_off_the_allowlist_are_not_followed() {
+        let hits = Arc::new(AtomicUsize::new(0));
+        let counter = hits.clone();
+        let elsewhere = spawn_origin(Router::new().route("/secret", get(move || async move {
+            counter.fetch_add(1, Ordering::SeqCst);
+            "secret"
+        })))
+        .await;
+        let origin = spawn_origin(Router::new().route("/seg.ts", get(move || async move {
+            axum::response::Redirect::temporary(&format!("http://localhost:{}/secret", elsewhere.port()))
+        })))
+        .await;
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            allowed_hosts: Some(allowlist::HostAllowlist::parse("127.0.0.1")),
+            ..Default::default()
+        });
+
+        let res = proxy(state, fetch_request(&format!("http://{origin}/seg.ts")).body(Body::empty()).unwrap()).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+        assert!(body_text(res).await.contains("redirected outside the allowlist"));
+        assert_eq!(hits.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn rate_limited_apps_build_outside_a_runtime() {
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            rate_limit: Some(1.0),
+            ..Default::default()
+        });
+        let _app = build_app(state);
+    }
+
+    #[test]
+    fn retry_delays_double_up_to_a_cap() {
+        assert_eq!(retry_delay(0), Duration::from_millis(100));
+        assert_eq!(retry_delay(3), Duration::from_millis(800));
+        assert_eq!(retry_delay(10), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(40), MAX_RETRY_DELAY);
+        assert_eq!(retry_delay(u32::MAX), MAX_RETRY_DELAY);
+    }
+
+    fn preflight(origin: &str) -> Request<Body> {
+        Request::options("/fetch?url=https%3A%2F%2Fcdn.example.com%2Fseg.ts")
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "GET")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "range,authorization")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn preflights_allow_any_origin_by_default() {
+        let res = proxy(test_state(), preflight("https://player.example.com")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert_eq!(res.headers()[header::ACCESS_CONTROL_ALLOW_METHODS], "*");
+        assert_eq!(res.headers()[header::ACCESS_CONTROL_ALLOW_HEADERS], "*");
+    }
+
+    #[tokio::test]
+    async fn preflights_only_allow_configured_origins() {
+        let state = AppState::new(config::Config {
+            block_private_addresses: false,
+            cors_origins: Some(vec![HeaderValue::from_static("https://player.example.com")]),
+            ..Default::default()
+        });
+
+        let res = proxy(state.clone(), preflight("https://player.example.com")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://player.example.com");
+        assert_eq!(res.headers()[header::ACCESS_CONTROL_ALLOW_METHODS], "*");
+        assert_eq!(res.headers()[header::ACCESS_CONTROL_ALLOW_HEADERS], "*");
+        assert!(res.headers()[header::VARY].to_str().unwrap().contains("origin"));
+
+        let res = proxy(state, preflight("https://elsewhere.example.net")).await;
+        assert!(!res.headers().contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+}
@@ -0,0 +1,271 @@
+use std::borrow::Cow;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hmac::{Hmac, KeyInit, Mac};
+use sha2::Sha256;
+use url::Url;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// HMAC-SHA256 of the upstream URL under `key`, base64url-encoded. This is the
+// `sig` value /fetch expects when PROXY_SIGNING_KEY is set, so services that
+// hand out proxied links can compute it with the same function.
+pub fn sign_url(key: &[u8], url: &str) -> String {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(url.as_bytes());
+    URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+}
+
+// Constant-time check of a `sig` parameter against the upstream URL.
+pub fn verify_signature(key: &[u8], url: &str, sig: &str) -> bool {
+    let Ok(sig) = URL_SAFE_NO_PAD.decode(sig.trim_end_matches('=')) else {
+        return false;
+    };
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(url.as_bytes());
+    mac.verify_slice(&sig).is_ok()
+}
+
+// Accepts both padded and unpadded base64url.
+pub fn decode_b64_url(value: &str) -> Option<String> {
+    let bytes = URL_SAFE_NO_PAD.decode(value.trim_end_matches('=')).ok()?;
+    String::from_utf8(bytes).ok()
+}
+
+// Percent-encodes a DASH URL template for a query parameter, leaving its
+// `$Identifier$` and `$Identifier%0Nd$` tokens as they are so the player can
+// still find and substitute them. Anything else between dollar signs is
+// encoded like the rest.
+fn encode_template(template: &str) -> String {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('$') {
+        out.push_str(&urlencoding::encode(&rest[..start]));
+        let after = &rest[start + 1..];
+        match after.find('$') {
+            Some(end) if is_template_identifier(&after[..end]) => {
+                out.push('$');
+                out.push_str(&after[..end]);
+                out.push('$');
+                rest = &after[end + 1..];
+            }
+            _ => {
+                out.push_str("%24");
+                rest = after;
+            }
+        }
+    }
+    out.push_str(&urlencoding::encode(rest));
+    out
+}
+
+// `Number`, `Time%05d` and so on; empty for the `$$` escape.
+fn is_template_identifier(token: &str) -> bool {
+    let (name, format) = match token.split_once('%') {
+        Some((name, format)) => (name, Some(format)),
+        None => (token, None),
+    };
+    let width = |format: &str| {
+        format.strip_prefix('0').and_then(|f| f.strip_suffix('d'))
+            .is_some_and(|width| !width.is_empty() && width.chars().all(|c| c.is_ascii_digit()))
+    };
+    name.chars().all(|c| c.is_ascii_alphanumeric()) && format.is_none_or(width)
+}
+
+// Whether two hosts are numbered shards of one CDN, e.g. cdn1.example.com and
+// cdn7.example.com: the same host once the digits of the first label are
+// dropped.
+fn same_shard_group(a: &str, b: &str) -> bool {
+    let split = |host: &str| {
+        let (first, rest) = host.split_once('.').unwrap_or((host, ""));
+        (first.chars().filter(|c| !c.is_ascii_digit()).collect::<String>().to_ascii_lowercase(), rest.to_ascii_lowercase())
+    };
+    split(a) == split(b)
+}
+
+// Whether `url` is what a player makes of DASH URL template `template`:
+// $Number$, $Time$, $Index$ and $Bandwidth$ stand for digits, $RepresentationID$
+// for a non-empty run without URL delimiters and $$ for a dollar sign. Any
+// other text, unknown identifiers included, has to match as is.
+pub fn matches_template(template: &str, url: &str) -> bool {
+    let mut pieces = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('$') {
+        pieces.push(TemplatePiece::Text(&rest[..start]));
+        let after = &rest[start + 1..];
+        let Some(end) = after.find('$') else {
+            pieces.push(TemplatePiece::Text(&rest[start..]));
+            rest = "";
+            break;
+        };
+        let token = &after[..end];
+        let name = token.split_once('%').map_or(token, |(name, _)| name);
+        pieces.push(match name {
+            "" => TemplatePiece::Text("$"),
+            "RepresentationID" if name == token => TemplatePiece::Id,
+            "Number" | "Time" | "Index" | "Bandwidth" if is_template_identifier(token) => TemplatePiece::Digits,
+            _ => TemplatePiece::Text(&rest[start..start + end + 2]),
+        });
+        rest = &after[end + 1..];
+    }
+    pieces.push(TemplatePiece::Text(rest));
+    match_pieces(&pieces, url)
+}
+
+enum TemplatePiece<'t> {
+    Text(&'t str),
+    Digits,
+    Id,
+}
+
+fn match_pieces(pieces: &[TemplatePiece], url: &str) -> bool {
+    let Some((piece, rest)) = pieces.split_first() else {
+        return url.is_empty();
+    };
+    let allowed = |c: char| match piece {
+        TemplatePiece::Digits => c.is_ascii_digit(),
+        _ => !matches!(c, '/' | '?' | '#' | '&' | '@' | ':' | '\\'),
+    };
+    match piece {
+        TemplatePiece::Text(text) => url.strip_prefix(text).is_some_and(|url| match_pieces(rest, url)),
+        // substituted values are short, backtracking over them is cheap
+        _ => url
+            .char_indices()
+            .take_while(|&(_, c)| allowed(c))
+            .any(|(i, c)| match_pieces(rest, &url[i + c.len_utf8()..])),
+    }
+}
+
+// Builds links back into this proxy for the URLs found in a rewritten response.
+#[derive(Default)]
+pub struct Links<'a> {
+    signing_key: Option<&'a [u8]>,
+    // the client's `ref_`, carried into every link so the whole stream is
+    // fetched with the same Referer
+    referer: Option<&'a str>,
+    // nesting depth the linked fetches run at, checked against PROXY_MAX_DEPTH
+    depth: Option<u32>,
+    // the client's `auth` token, segment fetches need it as much as the playlist
+    auth: Option<&'a str>,
+    // the client's `pin_host`, replacing the host of segment and variant
+    // links on other shards of the same CDN
+    pinned_host: Option<&'a str>,
+    // the client's explicit `no_referer` / `no_origin`, kept for the whole stream
+    no_referer: Option<bool>,
+    no_origin: Option<bool>,
+}
+
+impl<'a> Links<'a> {
+    pub fn new(signing_key: Option<&'a [u8]>) -> Self {
+        Links { signing_key, referer: None, depth: None, auth: None, pinned_host: None, no_referer: None, no_origin: None }
+    }
+
+    pub fn with_referer(mut self, referer: Option<&'a str>) -> Self {
+        self.referer = referer;
+        self
+    }
+
+    pub fn with_auth(mut self, auth: Option<&'a str>) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    pub fn with_pinned_host(mut self, host: Option<&'a str>) -> Self {
+        self.pinned_host = host;
+        self
+    }
+
+    pub fn with_omitted(mut self, no_referer: Option<bool>, no_origin: Option<bool>) -> Self {
+        self.no_referer = no_referer;
+        self.no_origin = no_origin;
+        self
+    }
+
+    pub fn with_depth(mut self, depth: u32) -> Self {
+        self.depth = Some(depth);
+        self
+    }
+
+    // base64url keeps the upstream query string intact no matter how players
+    // re-encode the link.
+    pub fn link(&self, target: &Url) -> String {
+        self.b64_link(target, self.referer)
+    }
+
+    // A link whose fetch sends `referer` instead of the target's own origin,
+    // unless the client asked for a specific `ref_`.
+    pub fn link_with_referer(&self, target: &Url, referer: &str) -> String {
+        self.b64_link(target, Some(self.referer.unwrap_or(referer)))
+    }
+
+    // A segment or variant playlist link sent to the pinned CDN shard, when
+    // the target is on another shard of the same CDN. Keys, init segments and
+    // everything on unrelated hosts keep their host, see `link`.
+    pub fn pinned_link(&self, target: &Url) -> String {
+        self.b64_link(&self.pin(target), self.referer)
+    }
+
+    fn b64_link(&self, target: &Url, referer: Option<&str>) -> String {
+        let mut link = format!("/fetch?b64={}", URL_SAFE_NO_PAD.encode(target.as_str()));
+        self.push_params(&mut link, target, referer);
+        link
+    }
+
+    // DASH SegmentTemplate identifiers like $Number$ are substituted by the
+    // player after the fact, so these links use a plain `url=` parameter and
+    // keep every identifier unescaped. The signature then covers the template,
+    // which goes along as `tpl` for /fetch to check the substituted URL against.
+    pub fn template_link(&self, target: &Url) -> String {
+        let encoded = encode_template(target.as_str());
+        // identifiers are the only dollar signs left unescaped
+        let is_template = encoded.contains('$');
+        let mut link = format!("/fetch?url={encoded}");
+        self.push_params(&mut link, target, self.referer);
+        if is_template && self.signing_key.is_some() {
+            link.push_str("&tpl=");
+            link.push_str(&URL_SAFE_NO_PAD.encode(target.as_str()));
+        }
+        link
+    }
+
+    // Sends the link to the pinned CDN shard, scheme, port and path unchanged.
+    fn pin<'u>(&self, target: &'u Url) -> Cow<'u, Url> {
+        match self.pinned_host {
+            Some(host) if target.host_str().is_some_and(|target| target != host && same_shard_group(target, host)) => {
+                let mut pinned = target.clone();
+                // validated when the pin_host parameter came in
+                let _ = pinned.set_host(Some(host));
+                Cow::Owned(pinned)
+            }
+            _ => Cow::Borrowed(target),
+        }
+    }
+
+    fn push_params(&self, link: &mut String, target: &Url, referer: Option<&str>) {
+        if let Some(key) = self.signing_key {
+            link.push_str("&sig=");
+            link.push_str(&sign_url(key, target.as_str()));
+        }
+        if let Some(depth) = self.depth {
+            link.push_str(&format!("&depth={depth}"));
+        }
+        if let Some(referer) = referer {
+            link.push_str("&ref_=");
+            link.push_str(&urlencoding::encode(referer));
+        }
+        if let Some(auth) = self.auth {
+            link.push_str("&auth=");
+            link.push_str(&urlencoding::encode(auth));
+        }
+        if let Some(host) = self.pinned_host {
+            link.push_str("&pin_host=");
+            link.push_str(&urlencoding::encode(host));
+        }
+        for (name, value) in [("no_referer", self.no_referer), ("no_origin", self.no_origin)] {
+            if let Some(value) = value {
+                link.push_str(&format!("&{name}={}", u8::from(value)));
+            }
+        }
+    }
+}
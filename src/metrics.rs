@@ -0,0 +1,98 @@
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// Upper bounds of the latency histogram buckets, in seconds.
+const LATENCY_BUCKETS: [f64; 10] = [0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+const STATUS_CLASSES: [&str; 5] = ["2xx", "3xx", "4xx", "5xx", "error"];
+
+// Process-wide counters rendered in the Prometheus text format by /metrics.
+#[derive(Default)]
+pub struct Metrics {
+    fetches: AtomicU64,
+    by_status: [AtomicU64; STATUS_CLASSES.len()],
+    bytes: AtomicU64,
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    latency_count: AtomicU64,
+    latency_sum_micros: AtomicU64,
+}
+
+impl Metrics {
+    // Records a fetch that got an upstream response with `status`.
+    pub fn record_response(&self, status: u16, latency: Duration) {
+        let class = match status {
+            200..=299 => 0,
+            300..=399 => 1,
+            400..=499 => 2,
+            _ => 3,
+        };
+        self.record(class, latency);
+    }
+
+    // Records a fetch that never got an upstream response.
+    pub fn record_error(&self, latency: Duration) {
+        self.record(4, latency);
+    }
+
+    pub fn add_bytes(&self, n: u64) {
+        self.bytes.fetch_add(n, Ordering::Relaxed);
+    }
+
+    fn record(&self, class: usize, latency: Duration) {
+        self.fetches.fetch_add(1, Ordering::Relaxed);
+        self.by_status[class].fetch_add(1, Ordering::Relaxed);
+
+        let secs = latency.as_secs_f64();
+        for (bucket, bound) in self.latency_buckets.iter().zip(LATENCY_BUCKETS) {
+            if secs <= bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.latency_count.fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_micros
+            .fetch_add(latency.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+
+        let _ = writeln!(out, "# HELP proxy_fetches_total Total /fetch requests sent upstream.");
+        let _ = writeln!(out, "# TYPE proxy_fetches_total counter");
+        let _ = writeln!(out, "proxy_fetches_total {}", self.fetches.load(Ordering::Relaxed));
+
+        let _ = writeln!(out, "# HELP proxy_fetches_by_status_total Fetches by upstream status class.");
+        let _ = writeln!(out, "# TYPE proxy_fetches_by_status_total counter");
+        for (class, count) in STATUS_CLASSES.iter().zip(&self.by_status) {
+            let _ = writeln!(
+                out,
+                "proxy_fetches_by_status_total{{class=\"{class}\"}} {}",
+                count.load(Ordering::Relaxed)
+            );
+        }
+
+        let _ = writeln!(out, "# HELP proxy_bytes_total Response body bytes proxied to clients.");
+        let _ = writeln!(out, "# TYPE proxy_bytes_total counter");
+        let _ = writeln!(out, "proxy_bytes_total {}", self.bytes.load(Ordering::Relaxed));
+
+        let _ = writeln!(out, "# HELP proxy_upstream_latency_seconds Time until the upstream response headers arrived.");
+        let _ = writeln!(out, "# TYPE proxy_upstream_latency_seconds histogram");
+        for (bucket, bound) in self.latency_buckets.iter().zip(LATENCY_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "proxy_upstream_latency_seconds_bucket{{le=\"{bound}\"}} {}",
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.latency_count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "proxy_upstream_latency_seconds_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(
+            out,
+            "proxy_upstream_latency_seconds_sum {}",
+            self.latency_sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "proxy_upstream_latency_seconds_count {count}");
+
+        out
+    }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use futures_util::future::{Either, Ready, ready};
+use tower::{Layer, Service};
+use tracing::warn;
+
+use crate::client_ip::{ForwardedHop, client_ip};
+use crate::error::ProxyError;
+
+// How often buckets that have refilled are dropped, on the next check.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+// Token bucket per client IP: `rate` tokens per second, holding at most `burst`.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<Buckets>,
+    // set once a request came in without a client address, see RateLimit
+    warned_unknown_client: AtomicBool,
+}
+
+struct Buckets {
+    by_ip: HashMap<IpAddr, Bucket>,
+    pruned: Instant,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        RateLimiter {
+            rate,
+            burst: burst.max(1.0),
+            buckets: Mutex::new(Buckets { by_ip: HashMap::new(), pruned: Instant::now() }),
+            warned_unknown_client: AtomicBool::new(false),
+        }
+    }
+
+    // Takes a token for `ip`, or returns how long until one is available.
+    pub fn check(&self, ip: IpAddr) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if now.duration_since(buckets.pruned) >= PRUNE_INTERVAL {
+            self.prune(&mut buckets, now);
+        }
+        let bucket = buckets.by_ip.entry(ip).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    // Drops buckets that have refilled completely; they'd start out full anyway.
+    fn prune(&self, buckets: &mut Buckets, now: Instant) {
+        buckets.by_ip.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * self.rate < self.burst
+        });
+        buckets.pruned = now;
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<RateLimiter>,
+    trust_forwarded: Option<ForwardedHop>,
+}
+
+impl RateLimitLayer {
+    pub fn new(limiter: Arc<RateLimiter>, trust_forwarded: Option<ForwardedHop>) -> Self {
+        RateLimitLayer { limiter, trust_forwarded }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimit<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimit {
+            inner,
+            limiter: self.limiter.clone(),
+            trust_forwarded: self.trust_forwarded,
+        }
+    }
+}
+
+// Answers `429 Too Many Requests` with a Retry-After once a client runs out of tokens.
+#[derive(Clone)]
+pub struct RateLimit<S> {
+    inner: S,
+    limiter: Arc<RateLimiter>,
+    trust_forwarded: Option<ForwardedHop>,
+}
+
+impl<S> Service<Request<Body>> for RateLimit<S>
+where
+    S: Service<Request<Body>, Response = Response>,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Either<Ready<Result<Response, S::Error>>, S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let ip = client_ip(req.headers(), req.extensions(), self.trust_forwarded);
+        // only when the server is run without connect info, which would
+        // leave every client unlimited
+        if ip.is_none() && !self.limiter.warned_unknown_client.swap(true, Ordering::Relaxed) {
+            warn!("request without a client address, not rate limiting it");
+        }
+
+        if let Some(ip) = ip
+            && let Err(wait) = self.limiter.check(ip)
+        {
+            let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+            let response = (
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                ProxyError::new(StatusCode::TOO_MANY_REQUESTS, "RATE_LIMITED", "Too many requests"),
+            )
+                .into_response();
+            return Either::Left(ready(Ok(response)));
+        }
+
+        Either::Right(self.inner.call(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bucket_allows_burst_then_limits() {
+        let limiter = RateLimiter::new(1.0, 2.0);
+        let ip = IpAddr::from([203, 0, 113, 7]);
+
+        assert!(limiter.check(ip).is_ok());
+        assert!(limiter.check(ip).is_ok());
+        let wait = limiter.check(ip).unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_secs(1));
+
+        // other clients have their own bucket
+        assert!(limiter.check(IpAddr::from([203, 0, 113, 8])).is_ok());
+    }
+
+    #[test]
+    fn refilled_buckets_are_dropped_on_a_later_check() {
+        let limiter = RateLimiter::new(1000.0, 1.0);
+        limiter.check(IpAddr::from([203, 0, 113, 7])).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+
+        let mut buckets = limiter.buckets.lock().unwrap();
+        buckets.pruned -= PRUNE_INTERVAL;
+        drop(buckets);
+        limiter.check(IpAddr::from([203, 0, 113, 8])).unwrap();
+        assert_eq!(limiter.buckets.lock().unwrap().by_ip.len(), 1);
+    }
+}
@@ -0,0 +1,193 @@
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::redirect::Policy;
+use url::{Host, Url};
+
+use crate::allowlist::HostAllowlist;
+
+// Returned whenever a target resolves to an address we refuse to fetch from.
+#[derive(Debug)]
+pub struct BlockedAddress(pub IpAddr);
+
+impl fmt::Display for BlockedAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "refusing to connect to non-public address {}", self.0)
+    }
+}
+
+impl Error for BlockedAddress {}
+
+// A redirect pointing at a host outside PROXY_ALLOWED_HOSTS.
+#[derive(Debug)]
+pub struct HostNotAllowed(pub String);
+
+impl fmt::Display for HostNotAllowed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "refusing to follow redirect to host {} not in the allowlist", self.0)
+    }
+}
+
+impl Error for HostNotAllowed {}
+
+// Loopback, private, link-local and unique-local ranges, plus the unspecified
+// address (which reaches localhost on most systems), carrier-grade NAT,
+// "this network", IETF protocol assignments and the benchmarking range.
+// IPv4 addresses embedded in IPv6, mapped or behind the NAT64 prefix, are
+// judged as the IPv4 address they reach.
+pub fn is_blocked_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            let [a, b, c, _] = v4.octets();
+            v4.is_private()
+                || v4.is_loopback()
+                || v4.is_link_local()
+                || v4.is_broadcast()
+                || a == 0 // 0.0.0.0/8, the unspecified address included
+                || (a == 100 && (b & 0xc0) == 64) // shared address space 100.64.0.0/10
+                || (a == 192 && b == 0 && c == 0) // 192.0.0.0/24
+                || (a == 198 && (b & 0xfe) == 18) // benchmarking 198.18.0.0/15
+        }
+        IpAddr::V6(v6) => {
+            if let Some(v4) = v6.to_ipv4_mapped() {
+                return is_blocked_ip(IpAddr::V4(v4));
+            }
+            // well-known NAT64 prefix 64:ff9b::/96
+            if let [0x64, 0xff9b, 0, 0, 0, 0, high, low] = v6.segments() {
+                let v4 = (u32::from(high) << 16) | u32::from(low);
+                return is_blocked_ip(IpAddr::V4(v4.into()));
+            }
+            let first = v6.segments()[0];
+            v6.is_loopback()
+                || v6.is_unspecified()
+                || (first & 0xfe00) == 0xfc00 // unique local fc00::/7
+                || (first & 0xffc0) == 0xfe80 // link local fe80::/10
+        }
+    }
+}
+
+// Resolves the URL's host and checks every address it maps to. The client's
+// resolver repeats this at connect time, so a DNS answer that changes in
+// between (rebinding) is still caught. Lookup failures are left for the
+// fetch itself to report.
+pub async fn check_url(url: &Url) -> Result<(), BlockedAddress> {
+    match url.host() {
+        Some(Host::Ipv4(ip)) => check_ip(IpAddr::V4(ip)),
+        Some(Host::Ipv6(ip)) => check_ip(IpAddr::V6(ip)),
+        Some(Host::Domain(domain)) => {
+            let port = url.port_or_known_default().unwrap_or(0);
+            if let Ok(addrs) = tokio::net::lookup_host((domain, port)).await {
+                for addr in addrs {
+                    check_ip(addr.ip())?;
+                }
+            }
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
+
+fn check_ip(ip: IpAddr) -> Result<(), BlockedAddress> {
+    if is_blocked_ip(ip) {
+        return Err(BlockedAddress(ip));
+    }
+    Ok(())
+}
+
+// True when a reqwest error was caused by one of our address checks, either
+// from the resolver or from the redirect policy.
+pub fn is_blocked_error(err: &reqwest::Error) -> bool {
+    caused_by::<BlockedAddress>(err)
+}
+
+// True when the redirect policy refused a hop for its host.
+pub fn is_host_not_allowed_error(err: &reqwest::Error) -> bool {
+    caused_by::<HostNotAllowed>(err)
+}
+
+fn caused_by<E: Error + 'static>(err: &reqwest::Error) -> bool {
+    let mut source = err.source();
+    while let Some(e) = source {
+        if e.is::<E>() {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+// DNS resolver that refuses names pointing at non-public addresses.
+pub struct GuardedResolver;
+
+impl Resolve for GuardedResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0)).await?.collect();
+            for addr in &addrs {
+                check_ip(addr.ip())?;
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+// Same hop limit as Policy::limited, but every hop has to be on an allowed
+// host, and with `block_private` IP-literal redirect targets are checked too
+// since those never go through the resolver.
+pub fn redirect_policy(max_redirects: usize, allowed_hosts: Option<HostAllowlist>, block_private: bool) -> Policy {
+    Policy::custom(move |attempt| {
+        if attempt.previous().len() >= max_redirects {
+            return attempt.error("too many redirects");
+        }
+        let host = attempt.url().host_str().unwrap_or_default().to_string();
+        if let Some(allowed) = &allowed_hosts
+            && !allowed.allows(&host)
+        {
+            return attempt.error(HostNotAllowed(host));
+        }
+        let literal = match attempt.url().host() {
+            Some(Host::Ipv4(ip)) => Some(IpAddr::V4(ip)),
+            Some(Host::Ipv6(ip)) => Some(IpAddr::V6(ip)),
+            _ => None,
+        };
+        match literal {
+            Some(ip) if block_private && is_blocked_ip(ip) => attempt.error(BlockedAddress(ip)),
+            _ => attempt.follow(),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blocked(ip: &str) -> bool {
+        is_blocked_ip(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn non_public_ranges_are_blocked() {
+        for ip in [
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254",
+            "0.0.0.0", "0.1.2.3", "100.64.0.1", "100.127.255.254", "192.0.0.8",
+            "198.18.0.1", "198.19.255.255", "255.255.255.255",
+            "::1", "::", "fd00::1", "fe80::1", "::ffff:10.0.0.1",
+            "64:ff9b::7f00:1", "64:ff9b::a9fe:a9fe", "64:ff9b::6440:1",
+        ] {
+            assert!(blocked(ip), "{ip}");
+        }
+    }
+
+    #[test]
+    fn public_addresses_are_allowed() {
+        for ip in [
+            "8.8.8.8", "100.63.255.255", "100.128.0.1", "192.0.1.1", "198.17.255.255",
+            "198.20.0.1", "2001:4860:4860::8888", "::ffff:8.8.8.8", "64:ff9b::808:808",
+        ] {
+            assert!(!blocked(ip), "{ip}");
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use url::Url;
+
+use crate::allowlist::HostAllowlist;
+
+// An origin-specific tweak to the upstream URL, applied before it is fetched.
+pub trait UrlTransform: Send + Sync {
+    fn apply(&self, url: &mut Url);
+}
+
+// `strip_params:a|b` drops the named query parameters, e.g. tracking ones
+// that defeat caching. The rest of the query is re-encoded.
+pub struct StripParams {
+    names: Vec<String>,
+}
+
+impl UrlTransform for StripParams {
+    fn apply(&self, url: &mut Url) {
+        if url.query().is_none() {
+            return;
+        }
+        let kept: Vec<(String, String)> = url
+            .query_pairs()
+            .filter(|(name, _)| !self.names.iter().any(|strip| strip == name))
+            .map(|(name, value)| (name.into_owned(), value.into_owned()))
+            .collect();
+        if kept.is_empty() {
+            url.set_query(None);
+        } else {
+            url.query_pairs_mut().clear().extend_pairs(kept);
+        }
+    }
+}
+
+// The transforms from PROXY_URL_TRANSFORMS, each applying to the hosts its
+// pattern matches, in the order given. Entries are `;`-separated
+// `<host pattern>=<transform>` pairs, e.g.
+// `*.cdn.example.com=strip_params:utm_source|utm_medium`.
+#[derive(Default)]
+pub struct Transforms {
+    rules: Vec<(HostAllowlist, Box<dyn UrlTransform>)>,
+}
+
+impl Transforms {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let mut rules = Vec::new();
+        for entry in value.split(';').map(str::trim).filter(|e| !e.is_empty()) {
+            let (hosts, spec) = entry
+                .split_once('=')
+                .ok_or_else(|| format!("invalid URL transform {entry:?}: expected host=transform"))?;
+            rules.push((HostAllowlist::parse(hosts), parse_transform(spec.trim())?));
+        }
+        Ok(Transforms { rules })
+    }
+
+    // Adds a transform for the hosts matching `hosts`, for embedders with
+    // their own UrlTransform implementations.
+    pub fn with(mut self, hosts: &str, transform: impl UrlTransform + 'static) -> Self {
+        self.rules.push((HostAllowlist::parse(hosts), Box::new(transform)));
+        self
+    }
+
+    pub fn apply(&self, url: &mut Url) {
+        for (hosts, transform) in &self.rules {
+            if url.host_str().is_some_and(|host| hosts.allows(host)) {
+                transform.apply(url);
+            }
+        }
+    }
+}
+
+fn parse_transform(spec: &str) -> Result<Box<dyn UrlTransform>, String> {
+    let (name, args) = spec.split_once(':').unwrap_or((spec, ""));
+    match name {
+        "strip_params" => Ok(Box::new(StripParams {
+            names: args.split('|').map(str::trim).filter(|a| !a.is_empty()).map(str::to_string).collect(),
+        })),
+        _ => Err(format!("unknown URL transform {name:?}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn transforms_apply_to_matching_hosts_only() {
+        let transforms = Transforms::parse("*.cdn.example.com=strip_params:utm_source|utm_medium").unwrap();
+
+        let mut url = Url::parse("https://edge1.cdn.example.com/seg.ts?utm_source=x&token=abc&utm_medium=y").unwrap();
+        transforms.apply(&mut url);
+        assert_eq!(url.as_str(), "https://edge1.cdn.example.com/seg.ts?token=abc");
+
+        let mut url = Url::parse("https://edge1.cdn.example.com/seg.ts?utm_source=x").unwrap();
+        transforms.apply(&mut url);
+        assert_eq!(url.as_str(), "https://edge1.cdn.example.com/seg.ts");
+
+        let mut other = Url::parse("https://other.example.org/seg.ts?utm_source=x").unwrap();
+        transforms.apply(&mut other);
+        assert_eq!(other.as_str(), "https://other.example.org/seg.ts?utm_source=x");
+    }
+
+    #[test]
+    fn unknown_transforms_are_rejected() {
+        assert!(Transforms::parse("cdn.example.com=rot13").is_err());
+        assert!(Transforms::parse("strip_params:a").is_err());
+    }
+}
@@ -0,0 +1,117 @@
+use url::Url;
+
+use crate::links::Links;
+
+// Rewrites absolute http(s) URLs found in WebVTT cue payloads to go through
+// /fetch, as well as thumbnail cues whose whole payload is a sprite image,
+// relative ones resolved against `base`. The header, timing lines and
+// NOTE/STYLE/REGION blocks are left as-is.
+pub fn rewrite_vtt(text: &str, base: &Url, links: &Links) -> String {
+    let mut out = Vec::new();
+    // whether the current block is a cue whose timing line we've passed
+    let mut in_payload = false;
+    // NOTE/STYLE/REGION blocks and the WEBVTT header run until the next blank line
+    let mut in_verbatim_block = false;
+
+    for line in text.lines() {
+        if line.trim().is_empty() {
+            in_payload = false;
+            in_verbatim_block = false;
+            out.push(line.to_string());
+            continue;
+        }
+        if !in_payload && !in_verbatim_block {
+            if line.starts_with("WEBVTT")
+                || line.starts_with("NOTE")
+                || line.starts_with("STYLE")
+                || line.starts_with("REGION")
+            {
+                in_verbatim_block = true;
+            } else if line.contains("-->") {
+                in_payload = true;
+            }
+            out.push(line.to_string());
+            continue;
+        }
+        if in_payload {
+            out.push(rewrite_sprite(line, base, links).unwrap_or_else(|| rewrite_urls(line, links)));
+        } else {
+            out.push(line.to_string());
+        }
+    }
+
+    out.join("\n")
+}
+
+// Thumbnail tracks point each cue at a region of a sprite sheet, as in
+// `sprite-001.jpg#xywh=0,0,160,90`. The fragment is for the player, so it
+// stays outside the link.
+fn rewrite_sprite(line: &str, base: &Url, links: &Links) -> Option<String> {
+    let line = line.trim();
+    let (image, region) = line.split_once("#xywh=")?;
+    if image.is_empty() || line.contains(char::is_whitespace) {
+        return None;
+    }
+    let resolved = base.join(image).ok()?;
+    Some(format!("{}#xywh={region}", links.link(&resolved)))
+}
+
+fn rewrite_urls(line: &str, links: &Links) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+
+    while let Some(start) = find_url_start(rest) {
+        out.push_str(&rest[..start]);
+        let candidate = &rest[start..];
+        let end = candidate
+            .find(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | '<' | '>' | ')'))
+            .unwrap_or(candidate.len());
+        match Url::parse(&candidate[..end]) {
+            Ok(url) => out.push_str(&links.link(&url)),
+            Err(_) => out.push_str(&candidate[..end]),
+        }
+        rest = &candidate[end..];
+    }
+
+    out.push_str(rest);
+    out
+}
+
+fn find_url_start(text: &str) -> Option<usize> {
+    match (text.find("http://"), text.find("https://")) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrites_urls_in_cue_payloads_only() {
+        let vtt = "WEBVTT\nX-TIMESTAMP-MAP=MPEGTS:900000,LOCAL:00:00:00.000\n\nNOTE see https://example.com/notes\n\n1\n00:00:01.000 --> 00:00:02.000 align:start\n<img src=\"https://img.example.com/a.png\"> hello\n";
+        let base = Url::parse("https://cdn.example.com/subs/en.vtt").unwrap();
+        let rewritten = rewrite_vtt(vtt, &base, &Links::default());
+        let lines: Vec<&str> = rewritten.lines().collect();
+
+        assert_eq!(lines[0], "WEBVTT");
+        assert_eq!(lines[1], "X-TIMESTAMP-MAP=MPEGTS:900000,LOCAL:00:00:00.000");
+        assert_eq!(lines[3], "NOTE see https://example.com/notes");
+        assert_eq!(lines[6], "00:00:01.000 --> 00:00:02.000 align:start");
+        let link = Links::default().link(&Url::parse("https://img.example.com/a.png").unwrap());
+        assert_eq!(lines[7], format!("<img src=\"{link}\"> hello"));
+    }
+
+    #[test]
+    fn sprite_cues_keep_their_region_fragment() {
+        let vtt = "WEBVTT\n\n00:00:00.000 --> 00:00:05.000\nsprite-001.jpg#xywh=0,0,160,90\n\n00:00:05.000 --> 00:00:10.000\nhttps://img.example.com/s.jpg#xywh=160,0,160,90\n";
+        let base = Url::parse("https://cdn.example.com/thumbs/thumbs.vtt").unwrap();
+        let rewritten = rewrite_vtt(vtt, &base, &Links::default());
+        let lines: Vec<&str> = rewritten.lines().collect();
+
+        let link = |url: &str| Links::default().link(&Url::parse(url).unwrap());
+        assert_eq!(lines[3], format!("{}#xywh=0,0,160,90", link("https://cdn.example.com/thumbs/sprite-001.jpg")));
+        assert_eq!(lines[6], format!("{}#xywh=160,0,160,90", link("https://img.example.com/s.jpg")));
+    }
+}
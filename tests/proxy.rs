@@ -0,0 +1,139 @@
+use std::net::SocketAddr;
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode, header};
+use axum::response::Response;
+use axum::routing::get;
+use axum::Router;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use myproxy::config::Config;
+use myproxy::{AppState, build_app, sign_url};
+use tower::ServiceExt;
+
+const MASTER: &str = include_str!("fixtures/hls/master.m3u8");
+const MEDIA: &str = include_str!("fixtures/hls/media.m3u8");
+
+async fn spawn_origin(origin: Router) -> SocketAddr {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, origin).await.unwrap() });
+    addr
+}
+
+// Static origin serving the HLS fixtures and a segment.
+async fn fixture_origin() -> SocketAddr {
+    spawn_origin(
+        Router::new()
+            .route("/vod/master.m3u8", get(|| async { MASTER }))
+            .route("/vod/720p/index.m3u8", get(|| async { MEDIA }))
+            .route(
+                "/vod/720p/seg-0.ts",
+                get(|| async { ([(header::CONTENT_TYPE, "application/octet-stream")], &b"\x47\x40\x00\x10"[..]) }),
+            ),
+    )
+    .await
+}
+
+async fn get_through_proxy(uri: &str) -> Response {
+    let state = AppState::new(Config {
+        block_private_addresses: false,
+        ..Default::default()
+    });
+    build_app(state)
+        .oneshot(Request::get(uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap()
+}
+
+fn fetch_uri(target: &str) -> String {
+    format!("/fetch?url={}", urlencoding::encode(target))
+}
+
+async fn body_text(res: Response) -> String {
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    String::from_utf8(body.to_vec()).unwrap()
+}
+
+// Upstream URL behind a rewritten /fetch?b64= link.
+fn link_target(link: &str) -> String {
+    let query = link.strip_prefix("/fetch?b64=").expect("not a proxied link");
+    let b64 = query.split('&').next().unwrap();
+    String::from_utf8(URL_SAFE_NO_PAD.decode(b64).unwrap()).unwrap()
+}
+
+#[tokio::test]
+async fn master_playlist_is_rewritten() {
+    let origin = fixture_origin().await;
+    let res = get_through_proxy(&fetch_uri(&format!("http://{origin}/vod/master.m3u8"))).await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()[header::CONTENT_TYPE], "application/vnd.apple.mpegurl");
+    let text = body_text(res).await;
+    let variants: Vec<_> = text.lines().filter(|line| line.starts_with("/fetch")).map(link_target).collect();
+    assert_eq!(
+        variants,
+        [
+            format!("http://{origin}/vod/720p/index.m3u8"),
+            "https://edge.example.net/vod/360p/index.m3u8?token=abc".to_string(),
+        ]
+    );
+    assert!(text.contains(r#"CODECS="avc1.64001f,mp4a.40.2""#));
+}
+
+#[tokio::test]
+async fn media_playlist_key_and_segments_are_rewritten() {
+    let origin = fixture_origin().await;
+    let res = get_through_proxy(&fetch_uri(&format!("http://{origin}/vod/720p/index.m3u8"))).await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    let text = body_text(res).await;
+
+    let key_line = text.lines().find(|line| line.starts_with("#EXT-X-KEY")).unwrap();
+    let key_link = key_line.split('"').nth(1).unwrap();
+    assert_eq!(link_target(key_link), format!("http://{origin}/vod/keys/key.bin"));
+    assert!(key_line.ends_with(",IV=0x00000000000000000000000000000001"));
+
+    let first_segment = text.lines().find(|line| line.starts_with("/fetch")).unwrap();
+    assert_eq!(link_target(first_segment), format!("http://{origin}/vod/720p/seg-0.ts"));
+    assert!(text.contains("#EXT-X-ENDLIST"));
+}
+
+#[tokio::test]
+async fn segment_is_passed_through_as_mpeg_ts() {
+    let origin = fixture_origin().await;
+    let res = get_through_proxy(&fetch_uri(&format!("http://{origin}/vod/720p/seg-0.ts"))).await;
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.headers()[header::CONTENT_TYPE], "video/mp2t");
+    let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(&body[..], b"\x47\x40\x00\x10");
+}
+
+#[tokio::test]
+async fn invalid_url_is_a_bad_request() {
+    let res = get_through_proxy(&fetch_uri("not a url")).await;
+
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(body_text(res).await, "Invalid URL");
+}
+
+#[tokio::test]
+async fn links_signed_outside_the_proxy_are_accepted() {
+    let origin = fixture_origin().await;
+    let key = b"shared-secret".to_vec();
+    let app = build_app(AppState::new(Config {
+        block_private_addresses: false,
+        signing_key: Some(key.clone()),
+        ..Default::default()
+    }));
+    let target = format!("http://{origin}/vod/720p/seg-0.ts");
+
+    let signed = format!("{}&sig={}", fetch_uri(&target), sign_url(&key, &target));
+    let res = app.clone().oneshot(Request::get(signed).body(Body::empty()).unwrap()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let forged = format!("{}&sig={}", fetch_uri(&target), sign_url(b"other-key", &target));
+    let res = app.oneshot(Request::get(forged).body(Body::empty()).unwrap()).await.unwrap();
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+}